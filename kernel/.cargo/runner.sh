@@ -20,6 +20,9 @@ cd ..
 KERNEL=$1
 echo "Kernel path: $KERNEL"
 
+# Embed the symbol table used for backtraces into the kernel.
+sh kernel/conf/ksyms.sh $KERNEL || exit 1
+
 # Clone the `limine` repository if we don't have it yet.
 if [ ! -d target/limine ]; then
     git clone $LIMINE_GIT_URL --depth=1 --branch v4.x-branch-binary target/limine
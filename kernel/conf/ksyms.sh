@@ -0,0 +1,38 @@
+#! /bin/sh
+#
+# Embeds the symbol table of the kernel into its `.ksyms` section, which is used
+# to symbolize backtraces (see kernel/src/backtrace.rs).
+# The runner (.cargo/runner.sh) calls this after every build, with the path to the kernel.
+# If this script is not saved with LF line-endings, it will break!
+
+set -e
+
+KERNEL=$1
+TABLE="$KERNEL.ksyms"
+
+# The size of the section is the third column of `objdump -h`, in hex.
+SIZE=$(objdump -h "$KERNEL" | awk '$2 == ".ksyms" { print $3 }')
+if [ -z "$SIZE" ]; then
+    echo "No .ksyms section in $KERNEL, backtraces won't be symbolized"
+    exit 0
+fi
+SIZE=$((0x$SIZE))
+
+# One `<address> <name>` line per function, sorted by address.
+nm --defined-only --numeric-sort --demangle "$KERNEL" \
+    | awk '$2 ~ /^[tT]$/ { address = $1; $1 = ""; $2 = ""; sub(/^ +/, ""); print address " " $0 }' \
+    > "$TABLE"
+
+# The kernel finds the end of the table by the first zero byte, so at least one has to be left.
+USED=$(wc -c < "$TABLE")
+if [ "$USED" -ge "$SIZE" ]; then
+    echo "The symbol table takes $USED bytes, which doesn't fit in the $SIZE bytes reserved for .ksyms!"
+    echo "Increase KSYMS_SIZE in kernel/src/backtrace.rs."
+    exit 1
+fi
+echo "Embedding symbol table ($USED/$SIZE bytes)"
+
+# Padded with zeroes to the exact size of the section, so nothing in the kernel has to move.
+truncate -s "$SIZE" "$TABLE"
+objcopy --update-section .ksyms="$TABLE" "$KERNEL"
+rm "$TABLE"
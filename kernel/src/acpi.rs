@@ -9,12 +9,26 @@ use x86_64::{
 use acpi::AcpiHandler as AcpiHandlerTrait;
 use acpi::{AcpiTables, PhysicalMapping};
 
+/// Offset of the `CENTURY` field in the FADT. The acpi crate does not expose it.
+const FADT_CENTURY_OFFSET: usize = 108;
+
 pub fn load_acpi(rsdp_addr: u64) -> AcpiTables<AcpiHandler> {
     unsafe {
         AcpiTables::from_rsdp(AcpiHandler::new(), rsdp_addr as usize).expect("Failed to load acpi table!")
     }
 }
 
+/// Returns the CMOS register that holds the century, or 0 if the FADT does not report one.
+pub fn century_register(tables: &AcpiTables<AcpiHandler>) -> u8 {
+    match tables.find_table::<acpi::fadt::Fadt>() {
+        Ok(fadt) if fadt.region_length() > FADT_CENTURY_OFFSET => unsafe {
+            let ptr = fadt.virtual_start().as_ptr() as *const u8;
+            core::ptr::read_unaligned(ptr.add(FADT_CENTURY_OFFSET))
+        },
+        _ => 0,
+    }
+}
+
 #[derive(Clone)]
 pub struct AcpiHandler;
 
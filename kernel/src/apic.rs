@@ -1,42 +1,175 @@
+//! Local APIC support. Every core has its own Local APIC, which delivers
+//! the timer interrupt, and later on IPIs and routed hardware interrupts.
+//! Both xAPIC (MMIO) and x2APIC (MSR) modes are supported through the `x2apic` crate.
+
+use core::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
 use x86_64::{
-    VirtAddr,
     PhysAddr,
-    structures::paging::{
-        page::Page,
-        frame::PhysFrame,
-    }
+    registers::model_specific::Msr,
 };
-use x2apic::lapic::{LocalApic, LocalApicBuilder};
+use x2apic::lapic::{LocalApic, LocalApicBuilder, TimerMode, TimerDivide};
+use spin::Mutex;
+
+use kernel_common::percpu::this_cpu;
+
 use crate::interrupts::LApicInterrupts;
+use crate::MAX_CPUS;
+
+const XAPIC_REGISTERS_SIZE: u64 = 4096;
+const XAPIC_ID_OFFSET: u64 = 0x20;
+const XAPIC_EOI_OFFSET: u64 = 0xB0;
+const XAPIC_ICR_LOW_OFFSET: u64 = 0x300;
+const XAPIC_ICR_HIGH_OFFSET: u64 = 0x310;
+const XAPIC_TIMER_CURRENT_OFFSET: u64 = 0x390;
+const X2APIC_ID_MSR: u32 = 0x802;
+const X2APIC_EOI_MSR: u32 = 0x80B;
+const X2APIC_ICR_MSR: u32 = 0x830;
+const X2APIC_TIMER_CURRENT_MSR: u32 = 0x839;
 
-static mut LAPIC: Option<LocalApic> = None;
+/// How long the timer calibration waits for, in milliseconds.
+const CALIBRATION_MS: u64 = 10;
 
+const LAPIC_INIT: Mutex<Option<LocalApic>> = Mutex::new(None);
+static LAPICS: [Mutex<Option<LocalApic>>; MAX_CPUS] = [LAPIC_INIT; MAX_CPUS];
+
+/// Virtual address of the xAPIC registers. Unused in x2APIC mode.
+static XAPIC_BASE: AtomicU64 = AtomicU64::new(0);
+static X2APIC_MODE: AtomicBool = AtomicBool::new(false);
+/// Timer ticks per millisecond, with the divider set to 16. Measured once by the bootstrap core.
+static TIMER_TICKS_PER_MS: AtomicU32 = AtomicU32::new(0);
+
+/// Maps the Local APIC registers. Must be called once on the bootstrap core,
+/// before [`init_local`] is called on any core.
 pub fn init(apic_phys_address: u64) {
-    let addr = apic_phys_address + crate::memory::hhdm_offset();
-    let page = Page::containing_address(VirtAddr::new_truncate(addr));
-    let frame = PhysFrame::containing_address(PhysAddr::new_truncate(addr));
-    unsafe {
-        crate::memory::map_page_to_frame(page, frame, None).unwrap();
-    }
+    let x2apic = raw_cpuid::CpuId::new().get_feature_info().map(|f| f.has_x2apic()).unwrap_or(false);
+    X2APIC_MODE.store(x2apic, Ordering::Release);
+
+    let addr = crate::memory::map_mmio(PhysAddr::new(apic_phys_address), XAPIC_REGISTERS_SIZE).as_u64();
+    XAPIC_BASE.store(addr, Ordering::Release);
+    debug!("Local APIC at {:#x} (x2APIC: {})", apic_phys_address, x2apic);
+}
 
+/// Enables the Local APIC of the current core and starts its periodic timer.
+/// The first core to call this calibrates the timer, so this should be called on
+/// the bootstrap core before the other cores are started.
+pub fn init_local(core_id: usize) {
     let mut lapic = LocalApicBuilder::new()
         .timer_vector(LApicInterrupts::TimerIndex as usize)
         .error_vector(LApicInterrupts::ErrorIndex as usize)
         .spurious_vector(LApicInterrupts::SpuriousIndex as usize)
-        .set_xapic_base(addr)
+        .timer_mode(TimerMode::OneShot)
+        .timer_divide(TimerDivide::Div16)
+        .timer_initial(0)
+        .set_xapic_base(XAPIC_BASE.load(Ordering::Acquire))
         .build()
         .unwrap_or_else(|err| panic!("{}", err));
 
     unsafe {
         lapic.enable();
+        lapic.disable_timer();
+    }
+    this_cpu().set_lapic_id(local_apic_id());
+
+    if TIMER_TICKS_PER_MS.load(Ordering::Acquire) == 0 {
+        let ticks_per_ms = calibrate_timer(&mut lapic);
+        TIMER_TICKS_PER_MS.store(ticks_per_ms, Ordering::Release);
+        debug!("Local APIC timer: {} ticks/ms", ticks_per_ms);
+    }
+    let ticks_per_ms = TIMER_TICKS_PER_MS.load(Ordering::Acquire);
+    let initial = (ticks_per_ms as u64 * 1000 / kernel_common::time::TIMER_FREQUENCY_HZ).max(1) as u32;
+
+    unsafe {
+        lapic.set_timer_mode(TimerMode::Periodic);
+        lapic.set_timer_initial(initial);
         lapic.enable_timer();
+    }
 
-        LAPIC = Some(lapic);
+    *LAPICS[core_id].lock() = Some(lapic);
+    trace!("Local APIC enabled on core {}!", core_id);
+}
+
+/// Measures how fast the timer counts down against the PIT.
+fn calibrate_timer(lapic: &mut LocalApic) -> u32 {
+    unsafe {
+        lapic.set_timer_initial(u32::MAX);
     }
+    crate::clock::pit::wait_ms(CALIBRATION_MS);
+    let elapsed = u32::MAX - timer_current();
+    unsafe {
+        lapic.set_timer_initial(0);
+    }
+    elapsed / CALIBRATION_MS as u32
+}
+
+fn timer_current() -> u32 {
+    if X2APIC_MODE.load(Ordering::Acquire) {
+        unsafe { Msr::new(X2APIC_TIMER_CURRENT_MSR).read() as u32 }
+    } else {
+        let addr = XAPIC_BASE.load(Ordering::Acquire) + XAPIC_TIMER_CURRENT_OFFSET;
+        unsafe { core::ptr::read_volatile(addr as *const u32) }
+    }
+}
+
+/// Returns the LAPIC id of the current core.
+pub fn local_apic_id() -> u32 {
+    if X2APIC_MODE.load(Ordering::Acquire) {
+        unsafe { Msr::new(X2APIC_ID_MSR).read() as u32 }
+    } else {
+        let addr = XAPIC_BASE.load(Ordering::Acquire) + XAPIC_ID_OFFSET;
+        unsafe { core::ptr::read_volatile(addr as *const u32) >> 24 }
+    }
+}
+
+/// Returns the id of the current core, from its per-CPU data.
+pub fn current_core() -> usize {
+    this_cpu().core_id()
 }
 
+/// Returns the LAPIC id of the given core, if its Local APIC has been initialized.
+pub fn lapic_id(core_id: usize) -> Option<u32> {
+    kernel_common::percpu::cpu(core_id)?.lapic_id()
+}
+
+/// Runs `f` with the Local APIC of the given core.
+/// Panics if that core's Local APIC has not been initialized.
+pub fn with_local_apic<T, F: FnOnce(&mut LocalApic) -> T>(core_id: usize, f: F) -> T {
+    let mut lock = LAPICS[core_id].lock();
+    f(lock.as_mut().expect("Local APIC not initialized!"))
+}
+
+/// In xAPIC mode, set while the previous IPI has not been accepted yet.
+const ICR_DELIVERY_PENDING: u32 = 1 << 12;
+
+/// Writes the Interrupt Command Register of the current core, which sends an IPI.
+/// `command` is the low half of the register, `dest` the LAPIC id of the target.
+/// This writes the register directly, so it does not need to take any locks,
+/// and can be used from interrupt handlers and while panicking. See [`crate::ipi`].
+pub fn write_icr(command: u32, dest: u32) {
+    if X2APIC_MODE.load(Ordering::Acquire) {
+        unsafe { Msr::new(X2APIC_ICR_MSR).write(((dest as u64) << 32) | command as u64); }
+    } else {
+        let base = XAPIC_BASE.load(Ordering::Acquire);
+        let low = (base + XAPIC_ICR_LOW_OFFSET) as *mut u32;
+        let high = (base + XAPIC_ICR_HIGH_OFFSET) as *mut u32;
+        // The two halves are separate registers, so nothing may send an IPI in between
+        x86_64::instructions::interrupts::without_interrupts(|| unsafe {
+            while core::ptr::read_volatile(low) & ICR_DELIVERY_PENDING != 0 {
+                core::hint::spin_loop();
+            }
+            core::ptr::write_volatile(high, dest << 24);
+            // Writing the low half sends the IPI
+            core::ptr::write_volatile(low, command);
+        });
+    }
+}
+
+/// Signals the end of an interrupt to the Local APIC of the current core.
+/// This writes the register directly, so it does not need to take any locks.
 pub fn end_of_interrupt() {
-    unsafe {
-        LAPIC.as_mut().unwrap().end_of_interrupt();
+    if X2APIC_MODE.load(Ordering::Acquire) {
+        unsafe { Msr::new(X2APIC_EOI_MSR).write(0); }
+    } else {
+        let addr = XAPIC_BASE.load(Ordering::Acquire) + XAPIC_EOI_OFFSET;
+        unsafe { core::ptr::write_volatile(addr as *mut u32, 0); }
     }
 }
@@ -0,0 +1,156 @@
+//! Stack backtraces, symbolized with a symbol table embedded in the kernel.
+//! The kernel is built with frame pointers (see `.cargo/config.toml`), so every frame starts with
+//! the `rbp` of its caller, followed by the return address. Following that chain walks the stack.
+//!
+//! The symbol table is written into the `.ksyms` section after linking, by `conf/ksyms.sh`.
+//! It is plain text, with one `<address in hex> <name>` line per function, sorted by address.
+//! Without it, backtraces only show addresses.
+
+use core::arch::asm;
+use core::fmt;
+use x86_64::VirtAddr;
+use x86_64::structures::paging::Page;
+
+/// Space reserved for the symbol table.
+const KSYMS_SIZE: usize = 1024 * 1024;
+/// Backtraces stop after this many frames, in case the frame chain is corrupted and loops.
+const MAX_FRAMES: usize = 32;
+
+// Filled in after linking. The symbol table is read through the linker symbols around it,
+// as the compiler would assume this is all zeroes.
+#[used]
+#[link_section = ".ksyms"]
+static KSYMS: [u8; KSYMS_SIZE] = [0; KSYMS_SIZE];
+
+extern "C" {
+    static __ksyms: u8;
+    static __ksyms_end: u8;
+    static __text: u8;
+    static __text_end: u8;
+}
+
+fn symbol_table() -> &'static [u8] {
+    let table = unsafe {
+        let start = &__ksyms as *const u8;
+        let end = &__ksyms_end as *const u8;
+        core::slice::from_raw_parts(start, end as usize - start as usize)
+    };
+    // The rest of the reserved space is zeroed
+    let len = table.iter().position(|byte| *byte == 0).unwrap_or(table.len());
+    &table[..len]
+}
+
+fn is_kernel_code(addr: u64) -> bool {
+    let (start, end) = unsafe { (&__text as *const u8 as u64, &__text_end as *const u8 as u64) };
+    (start..end).contains(&addr)
+}
+
+/// Returns the name of the function containing `addr`, and how far into the function it is.
+/// Returns None if `addr` isn't kernel code, or the kernel has no symbol table.
+pub fn symbolize(addr: u64) -> Option<(&'static str, u64)> {
+    if !is_kernel_code(addr) {
+        return None;
+    }
+    let mut found = None;
+    for line in symbol_table().split(|byte| *byte == b'\n') {
+        let Some((start, name)) = core::str::from_utf8(line).ok().and_then(|line| line.split_once(' ')) else {
+            continue;
+        };
+        let Ok(start) = u64::from_str_radix(start, 16) else {
+            continue;
+        };
+        if start > addr {
+            break;
+        }
+        found = Some((name, addr - start));
+    }
+    found
+}
+
+/// Returns the frame pointer of the function this is inlined into.
+#[inline(always)]
+fn frame_pointer() -> u64 {
+    let rbp: u64;
+    unsafe { asm!("mov {}, rbp", out(reg) rbp, options(nomem, nostack, preserves_flags)); }
+    rbp
+}
+
+/// Returns whether a frame can be read without faulting.
+/// Doesn't take the mapping lock, so it works while panicking.
+fn is_readable(addr: u64) -> bool {
+    let Ok(addr) = VirtAddr::try_new(addr) else {
+        return false;
+    };
+    crate::memory::is_mapped_unlocked(Page::containing_address(addr))
+        && crate::memory::is_mapped_unlocked(Page::containing_address(addr + 15u64))
+}
+
+/// A stack to be walked. Formatting it walks the stack, and prints every frame with its symbol.
+#[derive(Debug, Clone, Copy)]
+pub struct Backtrace {
+    /// The exact instruction the backtrace starts at, if known.
+    rip: Option<u64>,
+    rbp: u64,
+}
+
+impl Backtrace {
+    /// The backtrace of the callers of the current function.
+    #[inline(always)]
+    pub fn capture() -> Self {
+        Self {
+            rip: None,
+            rbp: frame_pointer(),
+        }
+    }
+
+    /// The backtrace of code that got interrupted at `rip`, with `rbp` as its frame pointer.
+    pub fn interrupted(rip: u64, rbp: u64) -> Self {
+        Self {
+            rip: Some(rip),
+            rbp,
+        }
+    }
+
+    /// Walks the stack, calling `f` with every return address.
+    fn walk(&self, mut f: impl FnMut(u64) -> fmt::Result) -> fmt::Result {
+        let mut rbp = self.rbp;
+        for _ in 0..MAX_FRAMES {
+            if rbp == 0 || rbp % 8 != 0 || !is_readable(rbp) {
+                break;
+            }
+            let (next, return_address) = unsafe { (*(rbp as *const u64), *((rbp + 8) as *const u64)) };
+            // Reached the end of the chain, or it is corrupted
+            if !is_kernel_code(return_address) {
+                break;
+            }
+            f(return_address)?;
+            // Stacks grow down, so the frames of callers are always higher up
+            if next <= rbp {
+                break;
+            }
+            rbp = next;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Backtrace {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "Backtrace:")?;
+        let mut index = 0;
+        let mut write_frame = |addr: u64, lookup: u64| {
+            write!(f, "{:>3}: {:#018x}", index, addr)?;
+            match symbolize(lookup) {
+                Some((name, offset)) => writeln!(f, " - {}+{:#x}", name, offset + addr - lookup)?,
+                None => writeln!(f, " - <unknown>")?,
+            }
+            index += 1;
+            Ok(())
+        };
+        if let Some(rip) = self.rip {
+            write_frame(rip, rip)?;
+        }
+        // Return addresses point past the call, which might be the start of the next function
+        self.walk(|addr| write_frame(addr, addr - 1))
+    }
+}
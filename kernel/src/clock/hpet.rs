@@ -0,0 +1,87 @@
+//! The High Precision Event Timer. We only use its main counter, as a clock source
+//! and to calibrate the TSC. The timers themselves are left disabled.
+
+use core::sync::atomic::{AtomicU64, Ordering};
+use x86_64::PhysAddr;
+use acpi_crate::{AcpiTables, HpetInfo};
+
+use crate::acpi::AcpiHandler;
+
+const REGISTERS_SIZE: u64 = 1024;
+const CAPABILITIES_OFFSET: u64 = 0x00;
+const CONFIG_OFFSET: u64 = 0x10;
+const MAIN_COUNTER_OFFSET: u64 = 0xF0;
+
+/// The main counter is 64 bits wide.
+const COUNT_SIZE_CAP: u64 = 1 << 13;
+const ENABLE_CNF: u64 = 1 << 0;
+
+/// Virtual address of the HPET registers, or 0 if there is no usable HPET.
+static HPET_BASE: AtomicU64 = AtomicU64::new(0);
+static HPET_FREQUENCY: AtomicU64 = AtomicU64::new(0);
+
+/// Looks up the HPET in the ACPI tables, maps it and starts its main counter.
+/// Returns false if there is no HPET, or if its counter is only 32 bits wide.
+pub fn init(tables: &AcpiTables<AcpiHandler>) -> bool {
+    let info = match HpetInfo::new(tables) {
+        Ok(info) => info,
+        Err(_) => return false,
+    };
+
+    let phys_addr = info.base_address as u64;
+    let addr = crate::memory::map_mmio(PhysAddr::new(phys_addr), REGISTERS_SIZE).as_u64();
+
+    let capabilities = unsafe { read_register(addr, CAPABILITIES_OFFSET) };
+    if capabilities & COUNT_SIZE_CAP == 0 {
+        // A 32 bit counter wraps within minutes, which would need locking to track
+        warn!("HPET counter is only 32 bits wide, not using it!");
+        return false;
+    }
+    // The period of the counter is in femtoseconds
+    let period_fs = capabilities >> 32;
+    let frequency = 1_000_000_000_000_000 / period_fs;
+
+    unsafe {
+        let config = read_register(addr, CONFIG_OFFSET);
+        write_register(addr, CONFIG_OFFSET, config | ENABLE_CNF);
+    }
+
+    HPET_FREQUENCY.store(frequency, Ordering::Release);
+    HPET_BASE.store(addr, Ordering::Release);
+    debug!("HPET at {:#x} running at {} Hz", phys_addr, frequency);
+    true
+}
+
+pub fn is_available() -> bool {
+    HPET_BASE.load(Ordering::Acquire) != 0
+}
+
+pub fn frequency_hz() -> u64 {
+    HPET_FREQUENCY.load(Ordering::Acquire)
+}
+
+/// Reads the main counter. Returns 0 if the HPET is not available.
+pub fn read_counter() -> u64 {
+    let base = HPET_BASE.load(Ordering::Acquire);
+    if base == 0 {
+        return 0;
+    }
+    unsafe { read_register(base, MAIN_COUNTER_OFFSET) }
+}
+
+/// Busy waits for `ms` milliseconds. Must only be called if the HPET is available.
+pub fn wait_ms(ms: u64) {
+    let start = read_counter();
+    let count = frequency_hz() * ms / 1000;
+    while read_counter().wrapping_sub(start) < count {
+        core::hint::spin_loop();
+    }
+}
+
+unsafe fn read_register(base: u64, offset: u64) -> u64 {
+    core::ptr::read_volatile((base + offset) as *const u64)
+}
+
+unsafe fn write_register(base: u64, offset: u64, value: u64) {
+    core::ptr::write_volatile((base + offset) as *mut u64, value)
+}
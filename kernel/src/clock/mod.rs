@@ -0,0 +1,36 @@
+//! Hardware timers used by the kernel.
+//! The best clock source available is registered as the time base of `kernel_common::time`:
+//! the invariant TSC if the CPU has one, otherwise the HPET. The PIT can't be read without
+//! locking, so it is only used for calibration.
+
+use kernel_common::time::{ClockSource, register_clock_source};
+use acpi_crate::AcpiTables;
+
+use crate::acpi::AcpiHandler;
+
+pub mod pit;
+pub mod hpet;
+pub mod tsc;
+
+/// Detects the available clock sources, and registers the best one.
+pub fn init(tables: &AcpiTables<AcpiHandler>) {
+    let has_hpet = hpet::init(tables);
+
+    if tsc::is_invariant() {
+        register_clock_source(ClockSource {
+            name: "tsc",
+            read: tsc::read,
+            frequency_hz: tsc::calibrate(),
+        });
+    } else if has_hpet {
+        register_clock_source(ClockSource {
+            name: "hpet",
+            read: hpet::read_counter,
+            frequency_hz: hpet::frequency_hz(),
+        });
+    } else {
+        warn!("No invariant TSC or HPET, time will only have timer tick resolution!");
+        return;
+    }
+    info!("Clock source: {}", kernel_common::time::clock_source_name());
+}
@@ -0,0 +1,41 @@
+//! The legacy Programmable Interval Timer.
+//! It's slow to access and not very precise, so we only use it to calibrate other timers.
+
+use x86_64::instructions::port::Port;
+
+/// The frequency the PIT counts down at, in Hz.
+pub const PIT_FREQUENCY: u64 = 1_193_182;
+
+const CHANNEL_2_DATA: u16 = 0x42;
+const COMMAND: u16 = 0x43;
+/// Controls the gate of channel 2, and exposes its output in bit 5.
+const CHANNEL_2_GATE: u16 = 0x61;
+
+/// Busy waits for `ms` milliseconds, using channel 2 in one-shot mode.
+/// The counter is only 16 bits, so this can wait for at most ~54 milliseconds.
+pub fn wait_ms(ms: u64) {
+    let count = PIT_FREQUENCY * ms / 1000;
+    assert!(count <= u16::MAX as u64, "PIT can't wait for {}ms!", ms);
+
+    let mut command: Port<u8> = Port::new(COMMAND);
+    let mut data: Port<u8> = Port::new(CHANNEL_2_DATA);
+    let mut gate: Port<u8> = Port::new(CHANNEL_2_GATE);
+
+    unsafe {
+        // Disable the speaker, and lower the gate so the count doesn't start yet
+        let value = gate.read() & 0xFC;
+        gate.write(value);
+
+        // Channel 2, lobyte/hibyte, mode 1 (hardware re-triggerable one-shot), binary
+        command.write(0b1011_0010);
+        data.write(count as u8);
+        data.write((count >> 8) as u8);
+
+        // Raising the gate starts the count. The output goes high once it reaches 0.
+        gate.write(value | 0x01);
+        while gate.read() & 0x20 == 0 {
+            core::hint::spin_loop();
+        }
+        gate.write(value);
+    }
+}
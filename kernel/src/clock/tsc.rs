@@ -0,0 +1,34 @@
+//! The Time Stamp Counter. When it is invariant, it ticks at a constant rate regardless
+//! of power states, and is the cheapest clock source there is.
+//! We assume the firmware started the TSCs of all cores in sync.
+
+use raw_cpuid::CpuId;
+
+/// How long the calibration measures for, in milliseconds.
+const CALIBRATION_MS: u64 = 10;
+
+/// Returns whether the TSC ticks at a constant rate.
+pub fn is_invariant() -> bool {
+    CpuId::new().get_advanced_power_mgmt_info().map(|info| info.has_invariant_tsc()).unwrap_or(false)
+}
+
+pub fn read() -> u64 {
+    unsafe { core::arch::x86_64::_rdtsc() }
+}
+
+/// Returns the frequency of the TSC in Hz. Uses the frequency reported by the CPU
+/// if there is one, otherwise it is measured against the HPET or the PIT.
+pub fn calibrate() -> u64 {
+    if let Some(frequency) = CpuId::new().get_tsc_info().and_then(|info| info.tsc_frequency()) {
+        return frequency;
+    }
+
+    let start = read();
+    if super::hpet::is_available() {
+        super::hpet::wait_ms(CALIBRATION_MS);
+    } else {
+        super::pit::wait_ms(CALIBRATION_MS);
+    }
+    let elapsed = read() - start;
+    elapsed * 1000 / CALIBRATION_MS
+}
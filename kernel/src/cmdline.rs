@@ -0,0 +1,30 @@
+//! Options passed on the kernel command line, set with `KERNEL_CMDLINE` in `conf/limine.cfg`.
+//! The command line is a list of `key=value` pairs separated by spaces:
+//! - `watchdog_ms=<n>`: how long a single task poll may take before the watchdog reports it.
+//! - `watchdog_panic=<0|1>`: whether the watchdog panics instead of just reporting.
+
+use core::time::Duration;
+use limine::LimineKernelFileRequest;
+
+use kernel_common::task_system::watchdog;
+
+static KERNEL_FILE_REQUEST: LimineKernelFileRequest = LimineKernelFileRequest::new(0);
+
+/// Applies the options on the kernel command line. Unknown options are ignored with a warning.
+pub fn init() {
+    let cmdline = KERNEL_FILE_REQUEST.get_response().get()
+        .and_then(|response| response.kernel_file.get())
+        .and_then(|file| file.cmdline.to_str())
+        .and_then(|cmdline| cmdline.to_str().ok())
+        .unwrap_or("");
+    debug!("Kernel command line: `{}`", cmdline);
+
+    for option in cmdline.split_whitespace() {
+        let (key, value) = option.split_once('=').unwrap_or((option, ""));
+        match (key, value.parse::<u64>()) {
+            ("watchdog_ms", Ok(ms)) => watchdog::set_budget(Duration::from_millis(ms)),
+            ("watchdog_panic", Ok(panic)) => watchdog::set_panic_on_timeout(panic != 0),
+            _ => warn!("Ignoring invalid kernel command line option `{}`", option),
+        }
+    }
+}
@@ -0,0 +1,402 @@
+//! Handlers for the architectural exceptions.
+//! Every exception enters through a small assembly stub, which saves all general purpose registers
+//! on top of the frame the CPU pushed, so every exception is reported the same way,
+//! with the full register state in an [`ExceptionContext`].
+//!
+//! A fault caused by a kernel thread only kills the task of that thread, see
+//! [`kernel_common::task_system::thread::fault_recovery`]. Any other fault is fatal,
+//! including faults in interrupt handlers, even when they run on the stack of a thread.
+//!
+//! The x86_64 crate doesn't expose the entries of the control protection (21) and hypervisor injection (28)
+//! exceptions, which can only happen with CET or SEV enabled anyway.
+
+use core::arch::global_asm;
+use core::fmt::{self, Write};
+use x86_64::VirtAddr;
+use x86_64::registers::control::Cr2;
+use x86_64::registers::rflags::RFlags;
+use x86_64::instructions::segmentation::{CS, Segment};
+use x86_64::structures::idt::{InterruptDescriptorTable, PageFaultErrorCode};
+use x86_64::structures::paging::Page;
+use x86_64::structures::paging::mapper::MapToError;
+
+use kernel_common::percpu;
+use kernel_common::task_system::task::try_with_task;
+use kernel_common::task_system::thread;
+
+use crate::backtrace::Backtrace;
+use crate::memory::stack::{self, StackKind};
+use crate::serial::SerialWriter;
+
+pub const DIVIDE_ERROR: u8 = 0;
+pub const DEBUG: u8 = 1;
+pub const NON_MASKABLE_INTERRUPT: u8 = 2;
+pub const BREAKPOINT: u8 = 3;
+pub const OVERFLOW: u8 = 4;
+pub const BOUND_RANGE_EXCEEDED: u8 = 5;
+pub const INVALID_OPCODE: u8 = 6;
+pub const DEVICE_NOT_AVAILABLE: u8 = 7;
+pub const DOUBLE_FAULT: u8 = 8;
+pub const INVALID_TSS: u8 = 10;
+pub const SEGMENT_NOT_PRESENT: u8 = 11;
+pub const STACK_SEGMENT_FAULT: u8 = 12;
+pub const GENERAL_PROTECTION_FAULT: u8 = 13;
+pub const PAGE_FAULT: u8 = 14;
+pub const X87_FLOATING_POINT: u8 = 16;
+pub const ALIGNMENT_CHECK: u8 = 17;
+pub const MACHINE_CHECK: u8 = 18;
+pub const SIMD_FLOATING_POINT: u8 = 19;
+pub const VIRTUALIZATION: u8 = 20;
+pub const VMM_COMMUNICATION: u8 = 29;
+pub const SECURITY_EXCEPTION: u8 = 30;
+
+pub fn exception_name(vector: u8) -> &'static str {
+    match vector {
+        DIVIDE_ERROR => "DIVIDE ERROR",
+        DEBUG => "DEBUG",
+        NON_MASKABLE_INTERRUPT => "NMI",
+        BREAKPOINT => "BREAKPOINT",
+        OVERFLOW => "OVERFLOW",
+        BOUND_RANGE_EXCEEDED => "BOUND RANGE EXCEEDED",
+        INVALID_OPCODE => "INVALID OPCODE",
+        DEVICE_NOT_AVAILABLE => "DEVICE NOT AVAILABLE",
+        DOUBLE_FAULT => "DOUBLE FAULT",
+        INVALID_TSS => "INVALID TSS",
+        SEGMENT_NOT_PRESENT => "SEGMENT NOT PRESENT",
+        STACK_SEGMENT_FAULT => "STACK SEGMENT FAULT",
+        GENERAL_PROTECTION_FAULT => "GENERAL PROTECTION FAULT",
+        PAGE_FAULT => "PAGE FAULT",
+        X87_FLOATING_POINT => "X87 FLOATING POINT",
+        ALIGNMENT_CHECK => "ALIGNMENT CHECK",
+        MACHINE_CHECK => "MACHINE CHECK",
+        SIMD_FLOATING_POINT => "SIMD FLOATING POINT",
+        VIRTUALIZATION => "VIRTUALIZATION",
+        VMM_COMMUNICATION => "VMM COMMUNICATION",
+        SECURITY_EXCEPTION => "SECURITY EXCEPTION",
+        _ => "UNKNOWN",
+    }
+}
+
+/// Returns whether the CPU pushes an error code for the exception.
+fn has_error_code(vector: u8) -> bool {
+    matches!(vector, DOUBLE_FAULT | INVALID_TSS | SEGMENT_NOT_PRESENT | STACK_SEGMENT_FAULT
+        | GENERAL_PROTECTION_FAULT | PAGE_FAULT | ALIGNMENT_CHECK | VMM_COMMUNICATION | SECURITY_EXCEPTION)
+}
+
+/// Returns whether the exception is only caused by the code that was running, so killing
+/// that code is enough to recover. The others mean the machine itself is in a bad state.
+fn is_recoverable(vector: u8) -> bool {
+    matches!(vector, DIVIDE_ERROR | OVERFLOW | BOUND_RANGE_EXCEEDED | INVALID_OPCODE | DEVICE_NOT_AVAILABLE
+        | STACK_SEGMENT_FAULT | GENERAL_PROTECTION_FAULT | PAGE_FAULT | X87_FLOATING_POINT
+        | ALIGNMENT_CHECK | SIMD_FLOATING_POINT)
+}
+
+/// Everything the exception stubs save, in the order it is on the stack.
+/// Changes to it are applied when the handler returns.
+#[derive(Debug)]
+#[repr(C)]
+pub struct ExceptionContext {
+    pub r15: u64,
+    pub r14: u64,
+    pub r13: u64,
+    pub r12: u64,
+    pub r11: u64,
+    pub r10: u64,
+    pub r9: u64,
+    pub r8: u64,
+    pub rbp: u64,
+    pub rdi: u64,
+    pub rsi: u64,
+    pub rdx: u64,
+    pub rcx: u64,
+    pub rbx: u64,
+    pub rax: u64,
+    pub vector: u64,
+    /// Zero for exceptions without an error code.
+    pub error_code: u64,
+    // Pushed by the CPU
+    pub rip: u64,
+    pub cs: u64,
+    pub rflags: u64,
+    pub rsp: u64,
+    pub ss: u64,
+}
+
+impl fmt::Display for ExceptionContext {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "RAX={:016x} RBX={:016x} RCX={:016x} RDX={:016x}", self.rax, self.rbx, self.rcx, self.rdx)?;
+        writeln!(f, "RSI={:016x} RDI={:016x} RBP={:016x} RSP={:016x}", self.rsi, self.rdi, self.rbp, self.rsp)?;
+        writeln!(f, "R8 ={:016x} R9 ={:016x} R10={:016x} R11={:016x}", self.r8, self.r9, self.r10, self.r11)?;
+        writeln!(f, "R12={:016x} R13={:016x} R14={:016x} R15={:016x}", self.r12, self.r13, self.r14, self.r15)?;
+        writeln!(f, "RIP={:016x} RFLAGS={:016x} CS={:04x} SS={:04x}", self.rip, self.rflags, self.cs, self.ss)
+    }
+}
+
+/// The report of an exception, which is the same whether it panics or kills a thread.
+struct ExceptionReport<'a> {
+    context: &'a ExceptionContext,
+    /// What the handler found out about the exception.
+    detail: Option<fmt::Arguments<'a>>,
+}
+
+impl fmt::Display for ExceptionReport<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let vector = self.context.vector as u8;
+        write!(f, "EXCEPTION: {} (vector {})", exception_name(vector), vector)?;
+        if let Some(cpu) = percpu::try_this_cpu() {
+            write!(f, " on core {}", cpu.core_id())?;
+            if let Some(task) = cpu.current_task() {
+                write!(f, " in task {}", task)?;
+                try_with_task(task, |task| match task.name() {
+                    Some(name) => write!(f, " ({})", name),
+                    None => Ok(()),
+                }).unwrap_or(Ok(()))?;
+            }
+        }
+        writeln!(f)?;
+        if let Some(detail) = self.detail {
+            writeln!(f, "{}", detail)?;
+        }
+        if has_error_code(vector) {
+            writeln!(f, "Error code: {:#x}", self.context.error_code)?;
+        }
+        write!(f, "{}{}", self.context, Backtrace::interrupted(self.context.rip, self.context.rbp))
+    }
+}
+
+/// Defines the entry stub of an exception. The CPU only pushes an error code for some exceptions,
+/// the stubs of the others push a zero in its place, so every exception ends up with the same
+/// [`ExceptionContext`] on the stack.
+macro_rules! exception_stub {
+    ($stub:ident, $vector:literal, error_code) => {
+        global_asm!(
+            ".pushsection .text",
+            concat!(".global ", stringify!($stub)),
+            concat!(stringify!($stub), ":"),
+            concat!("push ", $vector),
+            "jmp exception_common",
+            ".popsection",
+        );
+        extern "C" { fn $stub(); }
+    };
+    ($stub:ident, $vector:literal) => {
+        global_asm!(
+            ".pushsection .text",
+            concat!(".global ", stringify!($stub)),
+            concat!(stringify!($stub), ":"),
+            "push 0",
+            concat!("push ", $vector),
+            "jmp exception_common",
+            ".popsection",
+        );
+        extern "C" { fn $stub(); }
+    };
+}
+
+exception_stub!(exception_divide_error, 0);
+exception_stub!(exception_debug, 1);
+exception_stub!(exception_nmi, 2);
+exception_stub!(exception_breakpoint, 3);
+exception_stub!(exception_overflow, 4);
+exception_stub!(exception_bound_range_exceeded, 5);
+exception_stub!(exception_invalid_opcode, 6);
+exception_stub!(exception_device_not_available, 7);
+exception_stub!(exception_double_fault, 8, error_code);
+exception_stub!(exception_invalid_tss, 10, error_code);
+exception_stub!(exception_segment_not_present, 11, error_code);
+exception_stub!(exception_stack_segment_fault, 12, error_code);
+exception_stub!(exception_general_protection_fault, 13, error_code);
+exception_stub!(exception_page_fault, 14, error_code);
+exception_stub!(exception_x87_floating_point, 16);
+exception_stub!(exception_alignment_check, 17, error_code);
+exception_stub!(exception_machine_check, 18);
+exception_stub!(exception_simd_floating_point, 19);
+exception_stub!(exception_virtualization, 20);
+exception_stub!(exception_vmm_communication, 29, error_code);
+exception_stub!(exception_security_exception, 30, error_code);
+
+// Saves the registers that the stubs didn't, and hands the context to `exception_dispatch`.
+// The CPU aligned the stack to 16 bytes before pushing its 5 values, and with the error code,
+// the vector and the 15 registers, the stack is aligned again for the call.
+global_asm!(
+    ".pushsection .text",
+    ".global exception_common",
+    "exception_common:",
+    "push rax",
+    "push rbx",
+    "push rcx",
+    "push rdx",
+    "push rsi",
+    "push rdi",
+    "push rbp",
+    "push r8",
+    "push r9",
+    "push r10",
+    "push r11",
+    "push r12",
+    "push r13",
+    "push r14",
+    "push r15",
+    "mov rdi, rsp",
+    "cld",
+    "call {dispatch}",
+    "pop r15",
+    "pop r14",
+    "pop r13",
+    "pop r12",
+    "pop r11",
+    "pop r10",
+    "pop r9",
+    "pop r8",
+    "pop rbp",
+    "pop rdi",
+    "pop rsi",
+    "pop rdx",
+    "pop rcx",
+    "pop rbx",
+    "pop rax",
+    // The vector and the error code
+    "add rsp, 16",
+    "iretq",
+    ".popsection",
+    dispatch = sym exception_dispatch,
+);
+
+fn stub_address(stub: unsafe extern "C" fn()) -> VirtAddr {
+    VirtAddr::new(stub as usize as u64)
+}
+
+/// Points every exception entry of the IDT to its stub.
+pub fn install(idt: &mut InterruptDescriptorTable) {
+    unsafe {
+        idt.divide_error.set_handler_addr(stub_address(exception_divide_error));
+        idt.debug.set_handler_addr(stub_address(exception_debug));
+        idt.non_maskable_interrupt.set_handler_addr(stub_address(exception_nmi)).set_stack_index(crate::gdt::NMI_IST_INDEX);
+        idt.breakpoint.set_handler_addr(stub_address(exception_breakpoint));
+        idt.overflow.set_handler_addr(stub_address(exception_overflow));
+        idt.bound_range_exceeded.set_handler_addr(stub_address(exception_bound_range_exceeded));
+        idt.invalid_opcode.set_handler_addr(stub_address(exception_invalid_opcode));
+        idt.device_not_available.set_handler_addr(stub_address(exception_device_not_available));
+        idt.double_fault.set_handler_addr(stub_address(exception_double_fault)).set_stack_index(crate::gdt::DOUBLE_FAULT_IST_INDEX);
+        idt.invalid_tss.set_handler_addr(stub_address(exception_invalid_tss));
+        idt.segment_not_present.set_handler_addr(stub_address(exception_segment_not_present));
+        idt.stack_segment_fault.set_handler_addr(stub_address(exception_stack_segment_fault));
+        idt.general_protection_fault.set_handler_addr(stub_address(exception_general_protection_fault));
+        // Not on an IST stack, as page faults can nest
+        idt.page_fault.set_handler_addr(stub_address(exception_page_fault));
+        idt.x87_floating_point.set_handler_addr(stub_address(exception_x87_floating_point));
+        idt.alignment_check.set_handler_addr(stub_address(exception_alignment_check));
+        idt.machine_check.set_handler_addr(stub_address(exception_machine_check));
+        idt.simd_floating_point.set_handler_addr(stub_address(exception_simd_floating_point));
+        idt.virtualization.set_handler_addr(stub_address(exception_virtualization));
+        idt.vmm_communication_exception.set_handler_addr(stub_address(exception_vmm_communication));
+        idt.security_exception.set_handler_addr(stub_address(exception_security_exception));
+    }
+}
+
+extern "C" fn exception_dispatch(context: &mut ExceptionContext) {
+    match context.vector as u8 {
+        NON_MASKABLE_INTERRUPT => nmi(context),
+        DEBUG | BREAKPOINT => {
+            // Don't panic, execution just continues after these.
+            // The interrupted code might be holding the lock of the logger
+            let _ = writeln!(SerialWriter, "{}", ExceptionReport { context, detail: None });
+        },
+        PAGE_FAULT => page_fault(context),
+        DOUBLE_FAULT => double_fault(context),
+        _ => fault(context, None),
+    }
+}
+
+/// Returns whether the exception interrupted task code, rather than an interrupt handler that
+/// happened to run on the stack of a task. Killing the task wouldn't finish the handler,
+/// leaving its interrupt unacknowledged and its locks locked.
+fn in_task_context(context: &ExceptionContext) -> bool {
+    let in_interrupt = percpu::try_this_cpu().map(|cpu| cpu.in_interrupt()).unwrap_or(true);
+    // Interrupt gates run the handler with the kernel code segment
+    !in_interrupt && context.cs as u16 == CS::get_reg().0
+}
+
+/// Kills the thread that caused the exception, or panics if it can't be pinned on one.
+fn fault(context: &mut ExceptionContext, detail: Option<fmt::Arguments>) {
+    let vector = context.vector as u8;
+    if let Some(cpu) = percpu::try_this_cpu() {
+        cpu.record_rip(context.rip);
+    }
+    if is_recoverable(vector) && in_task_context(context) {
+        if let Some((rip, rsp)) = thread::fault_recovery(context.rsp as usize, vector) {
+            return kill_thread(context, detail, rip, rsp);
+        }
+    }
+    panic!("{}", ExceptionReport { context, detail });
+}
+
+/// Continues a thread that caused an exception at the place that switches it back to the executor for good.
+fn kill_thread(context: &mut ExceptionContext, detail: Option<fmt::Arguments>, rip: u64, rsp: u64) {
+    // The thread might have been holding the lock of the logger
+    let _ = writeln!(SerialWriter, "{}Killing the thread that caused it", ExceptionReport { context, detail });
+    context.rip = rip;
+    context.rsp = rsp;
+    // The thread might have disabled interrupts, but the executor expects them enabled
+    context.rflags |= RFlags::INTERRUPT_FLAG.bits();
+}
+
+fn nmi(context: &ExceptionContext) {
+    // Another core panicked, and is stopping everything
+    if crate::ipi::is_stopping() {
+        if let Some(cpu) = percpu::try_this_cpu() {
+            cpu.record_rip(context.rip);
+        }
+        crate::ipi::halt_stopped();
+    }
+    // An NMI can interrupt anything, including code holding the lock of the logger or framebuffer
+    let _ = writeln!(SerialWriter, "{}", ExceptionReport { context, detail: None });
+}
+
+fn double_fault(context: &mut ExceptionContext) {
+    // Page faults run on the current stack, so one on a guard page can't be delivered,
+    // and escalates to a double fault. CR2 still holds the address of the guard page then.
+    let addr = Cr2::read();
+    if let Some(stack) = stack::stack_for_guard_page(addr) {
+        // A kernel thread that overflows its stack is stopped, instead of taking down the kernel
+        if stack.kind == StackKind::Thread && in_task_context(context) {
+            if let Some((rip, rsp)) = thread::stack_overflow_recovery(stack.bottom.as_u64() as usize) {
+                return kill_thread(context, Some(format_args!("Stack overflow\nAccessed address: {:?}", addr)), rip, rsp);
+            }
+        }
+        panic!("{}", ExceptionReport {
+            context,
+            detail: Some(format_args!("Stack overflow on core {} ({:?} stack)\nAccessed address: {:?}", stack.core, stack.kind, addr)),
+        });
+    }
+    panic!("{}", ExceptionReport { context, detail: None });
+}
+
+fn page_fault(context: &mut ExceptionContext) {
+    let addr = Cr2::read();
+    let error_code = PageFaultErrorCode::from_bits_truncate(context.error_code);
+
+    // Faults on non-present pages inside a demand paged region are resolved by mapping a fresh frame.
+    // Note that we can't log anything on this path, as the logger might allocate on the heap,
+    // which could be the very thing that faulted.
+    let region = crate::memory::regions::find_region(addr);
+    if let Some(region) = region {
+        if region.kind.is_demand_paged() && !error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION) {
+            let page = Page::containing_address(addr);
+            match unsafe { crate::memory::map_page(page, Some(region.flags)) } {
+                // Another core might have mapped the page in the meantime
+                Ok(()) | Err(MapToError::PageAlreadyMapped(_)) => return,
+                Err(e) => return fault(context, Some(format_args!("Failed to map page in region `{}`: {:?}\nAccessed address: {:?}", region.name, e, addr))),
+            }
+        }
+    }
+
+    // Running off the end of the current stack double faults instead, but the guard page
+    // of another stack can still be hit here
+    if let Some(stack) = stack::stack_for_guard_page(addr) {
+        return fault(context, Some(format_args!("Guard page of the {:?} stack of core {} was accessed\nAccessed address: {:?}", stack.kind, stack.core, addr)));
+    }
+
+    let region_name = region.map(|r| r.name).unwrap_or("none");
+    fault(context, Some(format_args!("Accessed address: {:?}\nFlags: {:?}\nRegion: {}", addr, error_code, region_name)));
+}
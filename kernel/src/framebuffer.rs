@@ -22,6 +22,18 @@ pub fn fb_mut() -> MutexGuard<'static, FbWrapper> {
     FRAMEBUFFER.lock()
 }
 
+/// Takes the framebuffer even if it is locked, for the panic handler.
+/// The panicking core itself might be holding the lock, in the middle of logging.
+///
+/// This function is unsafe because the caller must guarantee that nothing else is using the
+/// framebuffer anymore, e.g. because every other core is stopped.
+pub unsafe fn fb_emergency() -> MutexGuard<'static, FbWrapper> {
+    if FRAMEBUFFER.is_locked() {
+        FRAMEBUFFER.force_unlock();
+    }
+    FRAMEBUFFER.lock()
+}
+
 pub struct Rect {
     pub x0: usize,
     pub y0: usize,
@@ -1,59 +1,61 @@
 use x86_64::VirtAddr;
 use x86_64::structures::tss::TaskStateSegment;
 use x86_64::structures::gdt::{GlobalDescriptorTable, Descriptor, SegmentSelector};
-use lazy_static::lazy_static;
+use spin::Once;
+
+use crate::MAX_CPUS;
+use crate::memory::stack::{StackKind, KERNEL_STACK_SIZE, IST_STACK_SIZE};
 
 pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
+pub const NMI_IST_INDEX: u16 = 1;
 
-/// The TSS is used to store the Interrupt Stack Table (IST)
-// TODO: Stack allocation should be handled by a memory function
-lazy_static! {
-    pub static ref TSS: TaskStateSegment = {
-        let mut tss = TaskStateSegment::new();
+/// Allocates a guard paged stack, and returns the address of its top, as the stack grows down.
+fn allocate_stack(size: usize, core_id: usize, kind: StackKind) -> VirtAddr {
+    crate::memory::stack::allocate_stack(size, core_id, kind)
+        .unwrap_or_else(|| panic!("Failed to allocate {:?} stack for core {}!", kind, core_id))
+        .top
+}
 
-        // Set stack for ring 0
-        // Note the lack of a guard page, so stack overflows could lead to memory corruption
-        tss.privilege_stack_table[0] = {
-            const STACK_SIZE: usize = 4096;
-            static mut RING0_STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
+const TSS_INIT: Once<TaskStateSegment> = Once::new();
+const GDT_INIT: Once<(GlobalDescriptorTable, Selectors)> = Once::new();
 
-            let stack_start = VirtAddr::from_ptr(unsafe { &RING0_STACK });
-            let stack_end = stack_start + STACK_SIZE;
-            stack_end
-        };
+/// Every core gets its own TSS, and with that its own interrupt stacks.
+/// The TSS is used to store the Interrupt Stack Table (IST)
+static TSS: [Once<TaskStateSegment>; MAX_CPUS] = [TSS_INIT; MAX_CPUS];
+/// Every core needs its own GDT, as the TSS descriptor points to the TSS of that core.
+static GDT: [Once<(GlobalDescriptorTable, Selectors)>; MAX_CPUS] = [GDT_INIT; MAX_CPUS];
+
+fn create_tss(core_id: usize) -> TaskStateSegment {
+    let mut tss = TaskStateSegment::new();
 
-        // Set up stack for the double fault interrupt
-        // Note the lack of a guard page, so stack overflows could lead to memory corruption
-        tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
-            const STACK_SIZE: usize = 4096;
-            static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
+    // Set stack for ring 0
+    tss.privilege_stack_table[0] = allocate_stack(KERNEL_STACK_SIZE, core_id, StackKind::Kernel);
 
-            let stack_start = VirtAddr::from_ptr(unsafe { &STACK });
-            let stack_end = stack_start + STACK_SIZE;
-            stack_end
-        };
+    // Exceptions that can be caused by a broken stack get their own stacks.
+    // Page faults can nest, e.g. while demand paging, and a nested fault would reset the stack
+    // pointer to the top of the IST stack, overwriting the outer frame. So they run on the current
+    // stack, and a page fault on a guard page escalates to a double fault, which reports it.
+    tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = allocate_stack(IST_STACK_SIZE, core_id, StackKind::DoubleFault);
+    tss.interrupt_stack_table[NMI_IST_INDEX as usize] = allocate_stack(IST_STACK_SIZE, core_id, StackKind::Nmi);
 
-        tss
-    };
+    tss
 }
 
-lazy_static! {
-    pub static ref GDT: (GlobalDescriptorTable, Selectors) = {
-        let mut gdt = GlobalDescriptorTable::new();
-        let kernel_code_selector = gdt.add_entry(Descriptor::kernel_code_segment());
-        let kernel_data_selector = gdt.add_entry(Descriptor::kernel_data_segment());
-        let user_code_selector = gdt.add_entry(Descriptor::user_code_segment());
-        let user_data_selector = gdt.add_entry(Descriptor::user_data_segment());
-        let tss_selector = gdt.add_entry(Descriptor::tss_segment(&TSS));
+fn create_gdt(tss: &'static TaskStateSegment) -> (GlobalDescriptorTable, Selectors) {
+    let mut gdt = GlobalDescriptorTable::new();
+    let kernel_code_selector = gdt.add_entry(Descriptor::kernel_code_segment());
+    let kernel_data_selector = gdt.add_entry(Descriptor::kernel_data_segment());
+    let user_code_selector = gdt.add_entry(Descriptor::user_code_segment());
+    let user_data_selector = gdt.add_entry(Descriptor::user_data_segment());
+    let tss_selector = gdt.add_entry(Descriptor::tss_segment(tss));
 
-        (gdt, Selectors {
-            kernel_code_selector,
-            kernel_data_selector,
-            user_code_selector,
-            user_data_selector,
-            tss_selector
-        })
-    };
+    (gdt, Selectors {
+        kernel_code_selector,
+        kernel_data_selector,
+        user_code_selector,
+        user_data_selector,
+        tss_selector
+    })
 }
 
 pub struct Selectors {
@@ -64,19 +66,29 @@ pub struct Selectors {
     pub tss_selector: SegmentSelector,
 }
 
-pub fn init() {
-    use x86_64::instructions::segmentation::{CS, DS};
+/// Builds and loads the GDT and TSS for the current core.
+/// Must be called exactly once on every core, before the IDT is loaded.
+pub fn init(core_id: usize) {
+    use x86_64::instructions::segmentation::{CS, DS, ES, SS};
     use x86_64::instructions::segmentation::Segment;
     use x86_64::instructions::tables::load_tss;
     use x86_64::instructions::interrupts::without_interrupts;
 
+    assert!(core_id < MAX_CPUS, "Core id {} exceeds MAX_CPUS!", core_id);
+    let tss = TSS[core_id].call_once(|| create_tss(core_id));
+    let (gdt, selectors) = GDT[core_id].call_once(|| create_gdt(tss));
+    kernel_common::percpu::this_cpu().set_tss(tss);
+
     without_interrupts(|| {
-        GDT.0.load();
+        gdt.load();
         unsafe {
-            CS::set_reg(GDT.1.kernel_code_selector);
-            DS::set_reg(GDT.1.kernel_data_selector);
-            load_tss(GDT.1.tss_selector);
+            CS::set_reg(selectors.kernel_code_selector);
+            DS::set_reg(selectors.kernel_data_selector);
+            ES::set_reg(selectors.kernel_data_selector);
+            // The bootloader's SS selector may not be valid in our GDT, which would fault on `iretq`
+            SS::set_reg(selectors.kernel_data_selector);
+            load_tss(selectors.tss_selector);
         }
     });
-    trace!("GDT enabled!");
+    trace!("GDT enabled on core {}!", core_id);
 }
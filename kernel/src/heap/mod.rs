@@ -1,11 +1,16 @@
 pub mod linked_list_alloc;
 use linked_list_alloc::*;
 
+use x86_64::VirtAddr;
+use crate::memory::regions::{reserve_region, VirtualRegion, RegionKind};
+
 #[global_allocator]
 static ALLOCATOR: LinkedListAlloc = LinkedListAlloc::empty();
 
 pub const HEAP_START:   usize = 0x_4444_4444_0000;
 pub const HEAP_SIZE:    usize = 1024 * 4096;
+/// The heap grows on demand up to this size. Pages are only mapped once they are touched.
+pub const HEAP_MAX_SIZE: usize = 1024 * 1024 * 1024;
 
 pub fn is_initialized() -> bool {
     ALLOCATOR.was_initialized()
@@ -13,6 +18,8 @@ pub fn is_initialized() -> bool {
 
 pub fn init() {
     if !is_initialized() {
+        reserve_region(VirtualRegion::new("heap", VirtAddr::new(HEAP_START as u64), HEAP_MAX_SIZE as u64, RegionKind::Heap))
+            .expect("Failed to reserve heap region!");
         unsafe {
             ALLOCATOR.init(HEAP_START, HEAP_SIZE);
         }
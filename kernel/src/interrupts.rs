@@ -1,6 +1,27 @@
-use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode};
+use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame};
 
 use lazy_static::lazy_static;
+use kernel_common::irq::{IRQ_VECTOR_BASE, IRQ_VECTOR_COUNT};
+
+// The handlers below are generated for a fixed list of vectors, which has to match the driver range
+const _: () = assert!(IRQ_VECTOR_BASE == 48 && IRQ_VECTOR_COUNT == 64);
+
+/// Installs a handler for every listed vector, which hands the interrupt to `kernel_common::irq`.
+/// The handler doesn't receive its vector, so every vector needs its own function.
+macro_rules! set_irq_handlers {
+    ($idt:ident, $($vector:literal),* $(,)?) => {
+        $({
+            extern "x86-interrupt" fn handler(_stack_frame: InterruptStackFrame) {
+                let cpu = kernel_common::percpu::this_cpu();
+                cpu.enter_interrupt();
+                kernel_common::irq::dispatch($vector);
+                crate::apic::end_of_interrupt();
+                cpu.leave_interrupt();
+            }
+            $idt[$vector].set_handler_fn(handler);
+        })*
+    };
+}
 
 #[derive(Debug, Clone, Copy)]
 #[repr(u8)]
@@ -8,6 +29,10 @@ pub enum LApicInterrupts {
     TimerIndex = 32,
     ErrorIndex,
     SpuriousIndex,
+    /// Sent to a halted core when one of its tasks is woken from another core.
+    WakeupIndex,
+    /// Asks a core to flush pages from its TLB, see [`crate::ipi::tlb_shootdown`].
+    TlbShootdownIndex,
 }
 
 impl LApicInterrupts {
@@ -19,26 +44,37 @@ impl LApicInterrupts {
 lazy_static! {
     pub static ref IDT: InterruptDescriptorTable = {
         let mut idt = InterruptDescriptorTable::new();
-        idt.breakpoint.set_handler_fn(breakpoint_handler);
-        unsafe {
-            idt.double_fault.set_handler_fn(double_fault_handler).set_stack_index(crate::gdt::DOUBLE_FAULT_IST_INDEX);
-        }
-        idt.page_fault.set_handler_fn(page_fault_handler);
-        idt.general_protection_fault.set_handler_fn(general_protection_fault_handler);
-        idt.stack_segment_fault.set_handler_fn(stack_segment_fault_handler);
+        crate::exceptions::install(&mut idt);
 
         // LAPIC interrupts
         idt[LApicInterrupts::TimerIndex.as_usize()].set_handler_fn(timer_handler);
         idt[LApicInterrupts::ErrorIndex.as_usize()].set_handler_fn(lapic_error_handler);
         idt[LApicInterrupts::SpuriousIndex.as_usize()].set_handler_fn(lapic_spurious_handler);
+        idt[LApicInterrupts::WakeupIndex.as_usize()].set_handler_fn(wakeup_handler);
+        idt[LApicInterrupts::TlbShootdownIndex.as_usize()].set_handler_fn(tlb_shootdown_handler);
+
+        // Hardware interrupts requested by drivers
+        set_irq_handlers!(idt,
+            48, 49, 50, 51, 52, 53, 54, 55, 56, 57, 58, 59, 60, 61, 62, 63,
+            64, 65, 66, 67, 68, 69, 70, 71, 72, 73, 74, 75, 76, 77, 78, 79,
+            80, 81, 82, 83, 84, 85, 86, 87, 88, 89, 90, 91, 92, 93, 94, 95,
+            96, 97, 98, 99, 100, 101, 102, 103, 104, 105, 106, 107, 108, 109, 110, 111,
+        );
 
         idt
     };
 }
 
-extern "x86-interrupt" fn timer_handler(_stack_frame: InterruptStackFrame) {
-    // trace!("TIMER");
+extern "x86-interrupt" fn timer_handler(stack_frame: InterruptStackFrame) {
+    let cpu = kernel_common::percpu::this_cpu();
+    cpu.enter_interrupt();
+    let core_id = cpu.core_id();
+    cpu.stats.timer_ticks.fetch_add(1, core::sync::atomic::Ordering::Relaxed);
+    cpu.record_rip(stack_frame.instruction_pointer.as_u64());
+    kernel_common::time::timer_tick(core_id);
+    kernel_common::task_system::watchdog::check(core_id, stack_frame.instruction_pointer.as_u64());
     crate::apic::end_of_interrupt();
+    cpu.leave_interrupt();
 }
 
 extern "x86-interrupt" fn lapic_error_handler(_stack_frame: InterruptStackFrame) {
@@ -47,7 +83,12 @@ extern "x86-interrupt" fn lapic_error_handler(_stack_frame: InterruptStackFrame)
 }
 
 extern "x86-interrupt" fn lapic_spurious_handler(_stack_frame: InterruptStackFrame) {
+    // Spurious interrupts must not be acknowledged with an EOI
     trace!("LAPIC SPURIOUS");
+}
+
+extern "x86-interrupt" fn wakeup_handler(_stack_frame: InterruptStackFrame) {
+    // Nothing to do, the interrupt itself already took the core out of `hlt`
     crate::apic::end_of_interrupt();
 }
 
@@ -56,35 +97,7 @@ pub fn init_idt() {
     trace!("IDT enabled!");
 }
 
-extern "x86-interrupt" fn breakpoint_handler(stack_frame: InterruptStackFrame) {
-    error!("EXCEPTION: BREAKPOINT\n{:#?}", stack_frame); //Don't panic, because the function does not return `!`
-}
-
-extern "x86-interrupt" fn double_fault_handler(stack_frame: InterruptStackFrame, _error_code: u64) -> ! {
-    panic!("EXCEPTION: DOUBLE FAULT\n{:#?}", stack_frame);
-}
-
-extern "x86-interrupt" fn page_fault_handler(stack_frame: InterruptStackFrame, error_code: PageFaultErrorCode) {
-    use x86_64::registers::control::Cr2;
-    let addr = Cr2::read();
-    error!("EXCEPTION: PAGE FAULT\n{:?}\nError code: {:?}\nAccessed address: {:?}", stack_frame, error_code, addr);
-    if error_code.contains(PageFaultErrorCode::INSTRUCTION_FETCH) {
-        let ptr = (addr.as_u64() - 0x400_000) as *mut u8;
-        error!("Instruction: 0x{:0X}", unsafe { *ptr });
-    }
-    // if error_code.is_empty() {
-    //     let page = x86_64::structures::paging::Page::containing_address(addr);
-    //     unsafe { crate::memory::map_page(page, None).expect("Failed to map page!"); }
-    //     debug!("Page now allocated!");
-    //     return;
-    // }
-    loop {} //Halt loop, as we cannot proceed with execution until our page fault has been resolved
-}
-
-extern "x86-interrupt" fn general_protection_fault_handler(stack_frame: InterruptStackFrame, error_code: u64) {
-    panic!("EXCEPTION: GENERAL PROTECTION FAULT\n{:#?}\nError code: {:?}", stack_frame, error_code);
-}
-
-extern "x86-interrupt" fn stack_segment_fault_handler(stack_frame: InterruptStackFrame, error_code: u64) {
-    error!("EXCEPTION: STACK SEGMENT FAULT\n{:#?}\nError code: {:?}", stack_frame, error_code);
+extern "x86-interrupt" fn tlb_shootdown_handler(_stack_frame: InterruptStackFrame) {
+    crate::ipi::process_tlb_shootdown();
+    crate::apic::end_of_interrupt();
 }
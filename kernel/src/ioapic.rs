@@ -0,0 +1,207 @@
+//! I/O APIC support. The I/O APICs route hardware interrupts (GSIs) to the Local APIC
+//! of a core. Legacy ISA IRQs are mapped to GSIs using the interrupt source overrides
+//! from the ACPI MADT.
+
+use alloc::vec::Vec;
+use x86_64::{
+    PhysAddr,
+    instructions::port::Port,
+};
+use x2apic::ioapic::{IoApic, IrqFlags, IrqMode, RedirectionTableEntry};
+use acpi_crate::platform::interrupt::{Apic, Polarity, TriggerMode};
+use spin::Mutex;
+
+/// The I/O APIC only has an index and a data register.
+const REGISTERS_SIZE: u64 = 0x20;
+/// Vector every I/O APIC entry points to before it gets routed. They are masked anyway.
+const UNROUTED_VECTOR: u8 = 0xE0;
+/// Vector the legacy PIC gets remapped to, so its spurious interrupts can't be mistaken for exceptions.
+const LEGACY_PIC_VECTOR_BASE: u8 = 0xF0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Trigger {
+    Edge,
+    Level,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Active {
+    High,
+    Low,
+}
+
+#[derive(Debug)]
+pub enum RouteError {
+    /// No I/O APIC handles this GSI.
+    NoIoApic(u32),
+    /// The core has no Local APIC, or its id does not fit in an I/O APIC destination.
+    InvalidCore(usize),
+}
+
+struct IoApicInfo {
+    inner: IoApic,
+    gsi_base: u32,
+    entries: u32,
+}
+
+// The I/O APIC registers are mapped globally, and only accessed with the lock held.
+unsafe impl Send for IoApicInfo {}
+
+#[derive(Debug, Clone, Copy)]
+struct IsaOverride {
+    isa_source: u8,
+    gsi: u32,
+    trigger: Trigger,
+    active: Active,
+}
+
+static IO_APICS: Mutex<Vec<IoApicInfo>> = Mutex::new(Vec::new());
+/// Only ever locked with interrupts disabled. Interrupt handlers don't look up overrides,
+/// they mask level triggered sources by their GSI.
+static ISA_OVERRIDES: Mutex<Vec<IsaOverride>> = Mutex::new(Vec::new());
+
+/// Masks the legacy PIC, maps every I/O APIC from the MADT and stores the interrupt source overrides.
+/// All I/O APIC entries start out masked.
+pub fn init(apic: &Apic) {
+    disable_legacy_pic();
+
+    let mut io_apics = IO_APICS.lock();
+    for io_apic in apic.io_apics.iter() {
+        let phys_addr = io_apic.address as u64;
+        let addr = crate::memory::map_mmio(PhysAddr::new(phys_addr), REGISTERS_SIZE).as_u64();
+
+        let mut inner = unsafe { IoApic::new(addr) };
+        // `IoApic::init` leaves the mask bit alone, and gives every pin its own vector,
+        // which wraps past 0xFF with enough pins. So every entry is written explicitly.
+        let entries = unsafe { inner.max_table_entry() as u32 + 1 };
+        for irq in 0..entries {
+            let mut entry = RedirectionTableEntry::default();
+            entry.set_mode(IrqMode::Fixed);
+            entry.set_flags(IrqFlags::MASKED);
+            entry.set_vector(UNROUTED_VECTOR);
+            unsafe { inner.set_table_entry(irq as u8, entry); }
+        }
+        debug!("I/O APIC {} handles GSI {}..{}", io_apic.id, io_apic.global_system_interrupt_base, io_apic.global_system_interrupt_base + entries);
+        io_apics.push(IoApicInfo {
+            inner,
+            gsi_base: io_apic.global_system_interrupt_base,
+            entries,
+        });
+    }
+    drop(io_apics);
+
+    x86_64::instructions::interrupts::without_interrupts(|| store_isa_overrides(apic));
+}
+
+fn store_isa_overrides(apic: &Apic) {
+    let mut overrides = ISA_OVERRIDES.lock();
+    for iso in apic.interrupt_source_overrides.iter() {
+        // ISA interrupts are edge triggered and active high, unless overridden
+        let trigger = match iso.trigger_mode {
+            TriggerMode::Level => Trigger::Level,
+            _ => Trigger::Edge,
+        };
+        let active = match iso.polarity {
+            Polarity::ActiveLow => Active::Low,
+            _ => Active::High,
+        };
+        trace!("ISA IRQ {} -> GSI {} ({:?}, active {:?})", iso.isa_source, iso.global_system_interrupt, trigger, active);
+        overrides.push(IsaOverride {
+            isa_source: iso.isa_source,
+            gsi: iso.global_system_interrupt,
+            trigger,
+            active,
+        });
+    }
+}
+
+/// Remaps the legacy 8259 PICs away from the exception vectors, and masks every IRQ on them.
+fn disable_legacy_pic() {
+    let mut master_command: Port<u8> = Port::new(0x20);
+    let mut master_data: Port<u8> = Port::new(0x21);
+    let mut slave_command: Port<u8> = Port::new(0xA0);
+    let mut slave_data: Port<u8> = Port::new(0xA1);
+
+    unsafe {
+        // ICW1: start initialization, expect ICW4
+        master_command.write(0x11);
+        slave_command.write(0x11);
+        // ICW2: vector offsets
+        master_data.write(LEGACY_PIC_VECTOR_BASE);
+        slave_data.write(LEGACY_PIC_VECTOR_BASE + 8);
+        // ICW3: slave is connected to IRQ 2
+        master_data.write(4);
+        slave_data.write(2);
+        // ICW4: 8086 mode
+        master_data.write(0x01);
+        slave_data.write(0x01);
+        // Mask everything
+        master_data.write(0xFF);
+        slave_data.write(0xFF);
+    }
+}
+
+/// Routes a legacy ISA IRQ to `vector` on the given core, taking the MADT overrides into account.
+pub fn route_isa_irq(irq: u8, vector: u8, core_id: usize) -> Result<(), RouteError> {
+    let (gsi, trigger, active) = isa_irq_to_gsi(irq);
+    route_gsi(gsi, vector, core_id, trigger, active)
+}
+
+/// Returns the GSI an ISA IRQ is connected to, with its trigger mode and polarity.
+pub fn isa_irq_to_gsi(irq: u8) -> (u32, Trigger, Active) {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        ISA_OVERRIDES.lock().iter()
+            .find(|iso| iso.isa_source == irq)
+            .map(|iso| (iso.gsi, iso.trigger, iso.active))
+            .unwrap_or((irq as u32, Trigger::Edge, Active::High))
+    })
+}
+
+/// Routes a GSI to `vector` on the given core, and unmasks it.
+pub fn route_gsi(gsi: u32, vector: u8, core_id: usize, trigger: Trigger, active: Active) -> Result<(), RouteError> {
+    let lapic_id = crate::apic::lapic_id(core_id).ok_or(RouteError::InvalidCore(core_id))?;
+    let dest = u8::try_from(lapic_id).map_err(|_| RouteError::InvalidCore(core_id))?;
+
+    let mut flags = IrqFlags::empty();
+    if trigger == Trigger::Level {
+        flags |= IrqFlags::LEVEL_TRIGGERED;
+    }
+    if active == Active::Low {
+        flags |= IrqFlags::LOW_ACTIVE;
+    }
+
+    let mut entry = RedirectionTableEntry::default();
+    entry.set_mode(IrqMode::Fixed);
+    entry.set_flags(flags);
+    entry.set_vector(vector);
+    entry.set_dest(dest);
+
+    with_io_apic(gsi, |io_apic, irq| unsafe {
+        io_apic.set_table_entry(irq, entry);
+        io_apic.enable_irq(irq);
+    })?;
+    trace!("GSI {} routed to vector {} on core {}", gsi, vector, core_id);
+    Ok(())
+}
+
+/// Masks a GSI, so it won't be delivered anymore.
+pub fn mask_gsi(gsi: u32) -> Result<(), RouteError> {
+    with_io_apic(gsi, |io_apic, irq| unsafe { io_apic.disable_irq(irq) })
+}
+
+/// Unmasks a previously routed GSI.
+pub fn unmask_gsi(gsi: u32) -> Result<(), RouteError> {
+    with_io_apic(gsi, |io_apic, irq| unsafe { io_apic.enable_irq(irq) })
+}
+
+// Interrupts are disabled while the lock is held, as interrupt handlers may mask GSIs
+fn with_io_apic<T, F: FnOnce(&mut IoApic, u8) -> T>(gsi: u32, f: F) -> Result<T, RouteError> {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let mut io_apics = IO_APICS.lock();
+        let info = io_apics.iter_mut()
+            .find(|info| gsi >= info.gsi_base && gsi < info.gsi_base + info.entries)
+            .ok_or(RouteError::NoIoApic(gsi))?;
+        let irq = (gsi - info.gsi_base) as u8;
+        Ok(f(&mut info.inner, irq))
+    })
+}
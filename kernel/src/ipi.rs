@@ -0,0 +1,194 @@
+//! Inter-processor interrupts: waking up halted executors, TLB shootdowns, and stopping
+//! every core when one of them panics.
+//! IPIs are only sent to cores that are online, meaning their Local APIC is initialized.
+//! The ICR destination shorthands are not used for that reason, as the bootloader parks
+//! the other cores in its own code.
+
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use x86_64::VirtAddr;
+use x86_64::instructions::tlb;
+use kernel_common::percpu::{self, this_cpu};
+
+use crate::interrupts::LApicInterrupts;
+use crate::MAX_CPUS;
+
+const PAGE_SIZE: u64 = 4096;
+/// Shootdowns of more pages than this flush the whole TLB instead of every page.
+const MAX_SHOOTDOWN_PAGES: u64 = 32;
+
+/// ICR delivery modes.
+const DELIVERY_FIXED: u32 = 0b000 << 8;
+const DELIVERY_NMI: u32 = 0b100 << 8;
+/// ICR level bit, which has to be set for everything but INIT de-asserts.
+const LEVEL_ASSERT: u32 = 1 << 14;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IpiTarget {
+    Core(usize),
+    All,
+    AllButSelf,
+}
+
+impl IpiTarget {
+    /// Calls `f` with the LAPIC id of every online core that is targeted.
+    fn for_each(self, mut f: impl FnMut(u32)) {
+        let self_id = this_cpu().core_id();
+        match self {
+            IpiTarget::Core(core_id) => if let Some(lapic_id) = crate::apic::lapic_id(core_id) {
+                f(lapic_id);
+            },
+            IpiTarget::All | IpiTarget::AllButSelf => for cpu in percpu::cpus() {
+                if self == IpiTarget::AllButSelf && cpu.core_id() == self_id {
+                    continue;
+                }
+                if let Some(lapic_id) = cpu.lapic_id() {
+                    f(lapic_id);
+                }
+            },
+        }
+    }
+}
+
+/// Sends an interrupt with the given vector.
+/// Doesn't take any locks, so it can be used from interrupt handlers.
+pub fn send(target: IpiTarget, vector: u8) {
+    target.for_each(|lapic_id| crate::apic::write_icr(DELIVERY_FIXED | LEVEL_ASSERT | vector as u32, lapic_id));
+}
+
+/// Sends a non-maskable interrupt.
+pub fn send_nmi(target: IpiTarget) {
+    target.for_each(|lapic_id| crate::apic::write_icr(DELIVERY_NMI | LEVEL_ASSERT, lapic_id));
+}
+
+/// Wakes up the executor of a core if it is halted.
+pub fn wake_core(core_id: usize) {
+    send(IpiTarget::Core(core_id), LApicInterrupts::WakeupIndex as u8);
+}
+
+// ---- TLB shootdown ----
+
+/// Only one shootdown can be in flight at a time.
+static SHOOTDOWN_LOCK: spin::Mutex<()> = spin::Mutex::new(());
+static SHOOTDOWN_START: AtomicU64 = AtomicU64::new(0);
+static SHOOTDOWN_PAGES: AtomicU64 = AtomicU64::new(0);
+/// The amount of cores that still have to flush their TLB.
+static SHOOTDOWN_ACKS: AtomicUsize = AtomicUsize::new(0);
+const SHOOTDOWN_PENDING_INIT: AtomicBool = AtomicBool::new(false);
+/// Set for every core that has to flush its TLB for the current shootdown.
+static SHOOTDOWN_PENDING: [AtomicBool; MAX_CPUS] = [SHOOTDOWN_PENDING_INIT; MAX_CPUS];
+
+fn flush_local(start: VirtAddr, pages: u64) {
+    if pages > MAX_SHOOTDOWN_PAGES {
+        tlb::flush_all();
+    } else {
+        for i in 0..pages {
+            tlb::flush(start + i * PAGE_SIZE);
+        }
+    }
+}
+
+/// Flushes the TLB of the current core, if a shootdown is waiting for it.
+/// Called by the interrupt handler, and by code that spins with interrupts disabled on a lock
+/// the core sending the shootdown might hold.
+pub fn process_tlb_shootdown() {
+    if SHOOTDOWN_PENDING[this_cpu().core_id()].swap(false, Ordering::AcqRel) {
+        let start = VirtAddr::new(SHOOTDOWN_START.load(Ordering::Acquire));
+        flush_local(start, SHOOTDOWN_PAGES.load(Ordering::Acquire));
+        SHOOTDOWN_ACKS.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+/// Flushes `pages` pages starting at `start` from the TLB of every online core,
+/// and waits until all of them did. Must be called after changing or removing mappings,
+/// as other cores might still have the old translation cached.
+/// Can be called with interrupts disabled, as long as every lock another core could be spinning on
+/// with interrupts disabled at the same time processes shootdowns while waiting, like the mapping lock.
+pub fn tlb_shootdown(start: VirtAddr, pages: u64) {
+    flush_local(start, pages);
+
+    // MAX_CPUS is 64, so the targets fit in a bitmask
+    let self_id = this_cpu().core_id();
+    let targets = percpu::cpus()
+        .filter(|cpu| cpu.core_id() != self_id && cpu.lapic_id().is_some())
+        .fold(0u64, |mask, cpu| mask | 1 << cpu.core_id());
+    if targets == 0 {
+        return;
+    }
+
+    // Another core might be waiting for us to flush with interrupts disabled,
+    // so keep handling its request while waiting for the lock
+    let lock = loop {
+        if let Some(lock) = SHOOTDOWN_LOCK.try_lock() {
+            break lock;
+        }
+        process_tlb_shootdown();
+        core::hint::spin_loop();
+    };
+
+    SHOOTDOWN_START.store(start.as_u64(), Ordering::Release);
+    SHOOTDOWN_PAGES.store(pages, Ordering::Release);
+    SHOOTDOWN_ACKS.store(targets.count_ones() as usize, Ordering::Release);
+    let targets = (0..MAX_CPUS).filter(|core_id| targets & (1 << core_id) != 0);
+    for core_id in targets.clone() {
+        SHOOTDOWN_PENDING[core_id].store(true, Ordering::Release);
+    }
+    for core_id in targets {
+        send(IpiTarget::Core(core_id), LApicInterrupts::TlbShootdownIndex as u8);
+    }
+
+    while SHOOTDOWN_ACKS.load(Ordering::Acquire) != 0 {
+        core::hint::spin_loop();
+    }
+    drop(lock);
+}
+
+// ---- Stopping all cores ----
+
+static STOPPING: AtomicBool = AtomicBool::new(false);
+const STOPPED_INIT: AtomicBool = AtomicBool::new(false);
+/// Set by every core once it got stopped.
+static STOPPED: [AtomicBool; MAX_CPUS] = [STOPPED_INIT; MAX_CPUS];
+/// How often [`stop_all_cores`] checks whether every core stopped, before giving up on them.
+const STOP_POLLS: usize = 10_000_000;
+
+/// Halts every other core with an NMI, which can't be masked, so even cores
+/// stuck with interrupts disabled stop. Used by the panic handler.
+/// Waits a little for the cores to stop, and returns whether all of them did.
+pub fn stop_all_cores() -> bool {
+    STOPPING.store(true, Ordering::SeqCst);
+    send_nmi(IpiTarget::AllButSelf);
+
+    let self_id = this_cpu().core_id();
+    let all_stopped = || percpu::cpus()
+        .filter(|cpu| cpu.core_id() != self_id && cpu.lapic_id().is_some())
+        .all(|cpu| is_stopped(cpu.core_id()));
+    for _ in 0..STOP_POLLS {
+        if all_stopped() {
+            return true;
+        }
+        core::hint::spin_loop();
+    }
+    false
+}
+
+/// Returns whether the cores are being stopped. The NMI handler then halts the core it runs on.
+pub fn is_stopping() -> bool {
+    STOPPING.load(Ordering::SeqCst)
+}
+
+/// Returns whether a core got stopped by [`stop_all_cores`].
+pub fn is_stopped(core_id: usize) -> bool {
+    STOPPED.get(core_id).map(|stopped| stopped.load(Ordering::SeqCst)).unwrap_or(false)
+}
+
+/// Stops the current core for good. Interrupts stay disabled, so only another NMI can wake it,
+/// after which it halts again.
+pub fn halt_stopped() -> ! {
+    if let Some(cpu) = percpu::try_this_cpu() {
+        STOPPED[cpu.core_id()].store(true, Ordering::SeqCst);
+    }
+    loop {
+        x86_64::instructions::interrupts::disable();
+        x86_64::instructions::hlt();
+    }
+}
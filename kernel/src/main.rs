@@ -33,6 +33,14 @@ mod memory;
 mod heap;
 mod acpi;
 mod apic;
+mod ioapic;
+mod clock;
+mod platform;
+mod ipi;
+mod serial;
+mod backtrace;
+mod exceptions;
+mod cmdline;
 
 use limine::*;
 
@@ -56,12 +64,18 @@ pub extern "C" fn _start() -> ! {
 }
 
 pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+pub use kernel_common::MAX_CPUS;
 
 fn kernel_main(boot_info: &LimineBootInfoResponse) -> ! {
+    // The bootstrap core always gets core id 0.
+    kernel_common::percpu::init(0);
+    serial::init();
     framebuffer::init();
     framebuffer::fb_mut().set_clear_color([32,32,32]);
     framebuffer::fb_mut().clear();
     kernel_common::logger::init(log::LevelFilter::max(), &logger::LOGGER);
+    kernel_common::platform::init(&platform::PLATFORM);
+    cmdline::init();
     info!("Hello kernel! Version: {}", VERSION);
     info!(
         "Booted by {} v{}",
@@ -72,40 +86,51 @@ fn kernel_main(boot_info: &LimineBootInfoResponse) -> ! {
     let cpuid = CpuId::new();
     debug!("Running on: {:?}", cpuid.get_vendor_info());
 
-    gdt::init();
-    interrupts::init_idt();
-
     memory::init();
     info!("Memory mapped!");
 
+    // The GDT and IDT have to be set up before the heap, as the heap relies on page faults to grow.
+    gdt::init(0);
+    interrupts::init_idt();
+
     heap::init();
     info!("Heap initialized!");
 
-    // Loading the ACPI tables works fine, but initializing the APIC currently fails.
-    // Probably a mistake in my memory allocator, but I don't yet need the APIC anyway.
     static RSDP_REQUEST: LimineRsdpRequest = LimineRsdpRequest::new(0);
     let rsdp_addr = RSDP_REQUEST.get_response().get().unwrap().address.as_ptr().unwrap() as u64;
     let acpi_tables = acpi::load_acpi(rsdp_addr);
+    clock::init(&acpi_tables);
+    kernel_common::rtc::init(acpi::century_register(&acpi_tables));
+    kernel_common::wall_clock::init();
     let platform_info = acpi_tables.platform_info().expect("Failed to read platform info!");
     debug!("Processors found: {}", platform_info.processor_info.as_ref().map(|pi| pi.application_processors.len() + 1).unwrap_or(1));
-    /*
     if let acpi_crate::InterruptModel::Apic(apic) = &platform_info.interrupt_model {
         apic::init(apic.local_apic_address);
+        // The bootstrap core calibrates the timer, so it has to be set up before the other cores start
+        apic::init_local(0);
+        ioapic::init(apic);
+        x86_64::instructions::interrupts::enable();
     } else {
         panic!("Unsupported interrupt model! Only APIC is currently supported.");
     }
-    */
 
     let bsp_processor_id = platform_info.processor_info.as_ref().map(|pi| pi.boot_processor.processor_uid).unwrap();
     static SMP_REQUEST: LimineSmpRequest = LimineSmpRequest::new(0).flags(1);
     if let Some(smp_response) = SMP_REQUEST.get_response().get_mut() {
         info!("SMP cpus: {}", smp_response.cpus().len());
         let mut main_cpu_info = None;
-        for (i, cpu) in smp_response.cpus().iter_mut().enumerate() {
-            cpu.extra_argument = i as u64;
+        let mut next_core_id = 1;
+        for cpu in smp_response.cpus().iter_mut() {
             if cpu.processor_id != bsp_processor_id {
+                if next_core_id >= MAX_CPUS {
+                    warn!("Not starting cpu {}, as we only support {} cores!", cpu.processor_id, MAX_CPUS);
+                    continue;
+                }
+                cpu.extra_argument = next_core_id as u64;
+                next_core_id += 1;
                 cpu.goto_address = smp_main;
             } else {
+                cpu.extra_argument = 0;
                 main_cpu_info = Some(cpu);
             }
         }
@@ -119,6 +144,15 @@ fn kernel_main(boot_info: &LimineBootInfoResponse) -> ! {
 extern "C" fn smp_main(info: *const LimineSmpInfo) -> ! {
     let info: &'static LimineSmpInfo = unsafe { info.as_ref().unwrap() };
     let processor_id = info.extra_argument as usize;
+    // The bootstrap core already set up its per-CPU data and descriptor tables in `kernel_main`.
+    // Application processors start with whatever the bootloader left them, so set up our own.
+    if processor_id != 0 {
+        kernel_common::percpu::init(processor_id);
+        gdt::init(processor_id);
+        interrupts::init_idt();
+        apic::init_local(processor_id);
+        x86_64::instructions::interrupts::enable();
+    }
     info!("Hello from cpu {}!", processor_id);
 
     // Create the async executor for this core
@@ -127,13 +161,13 @@ extern "C" fn smp_main(info: *const LimineSmpInfo) -> ! {
     // Add the spawner for this core to the global scheduler
     scheduler_add_spawner(spawner.clone());
     // Spawn the kernel_stage_2_main task on the current core
-    spawner.spawn(kernel_stage_2_main(spawner.clone(), processor_id));
+    spawner.spawn(kernel_stage_2_main(spawner.clone()));
     // Run the executor
     executor.run()
 }
 
-async fn kernel_stage_2_main(spawner: Spawner, processor_id: usize) {
-    kernel_async::Kernel::builder(spawner, processor_id)
+async fn kernel_stage_2_main(spawner: Spawner) {
+    kernel_async::Kernel::builder(spawner)
         .build().await
         .run().await;
 }
@@ -0,0 +1,154 @@
+//! A bitmap based physical frame allocator.
+//! Every usable 4KiB frame reported by the bootloader gets a single bit,
+//! where a set bit means the frame is in use. The bitmap itself is stored
+//! in the first usable region that is large enough to hold it.
+
+use limine::*;
+use x86_64::PhysAddr;
+use x86_64::structures::paging::{FrameAllocator, FrameDeallocator, PhysFrame, Size4KiB};
+
+const FRAME_SIZE: u64 = 4096;
+const BITS_PER_WORD: usize = u64::BITS as usize;
+
+pub struct BitmapFrameAllocator {
+    bitmap: &'static mut [u64],
+    frame_count: usize,
+    free_frames: usize,
+    /// Word index to start searching from. Everything below this word is known to be in use.
+    next: usize,
+    /// Only the usable regions of this are ever handed out. Frees outside of them are refused,
+    /// as a set bit doesn't tell a reserved frame apart from an allocated one.
+    memory_map: &'static LimineMemmapResponse,
+    /// Frames holding the bitmap itself. These are in a usable region, but never handed out.
+    bitmap_frames: core::ops::Range<usize>,
+}
+
+impl BitmapFrameAllocator {
+    /// Create a FrameAllocator from the passed memory map.
+    ///
+    /// This function is unsafe because the caller must guarantee that the passed
+    /// memory map is valid, and that the complete physical memory is mapped at
+    /// `physical_memory_offset`. The main requirement is that all frames that are
+    /// marked as `USABLE` in it are really unused.
+    pub unsafe fn init(memory_map: &'static LimineMemmapResponse, physical_memory_offset: u64) -> Self {
+        // Bootloader reclaimable memory still contains the memory map, the SMP info and the
+        // page tables we are running on, so we can't hand those frames out (yet).
+        let usable_regions = || memory_map.memmap().iter()
+            .filter(|r| r.typ == LimineMemoryMapEntryType::Usable);
+
+        let highest_addr = usable_regions().map(|r| r.base + r.len).max().expect("No usable memory found!");
+        let frame_count = (highest_addr / FRAME_SIZE) as usize;
+        let word_count = (frame_count + BITS_PER_WORD - 1) / BITS_PER_WORD;
+        let bitmap_size = (word_count * core::mem::size_of::<u64>()) as u64;
+        let bitmap_frames = (bitmap_size + FRAME_SIZE - 1) / FRAME_SIZE;
+
+        let bitmap_region = usable_regions()
+            .find(|r| r.len >= bitmap_frames * FRAME_SIZE)
+            .expect("No usable region large enough to hold the frame bitmap!");
+        let bitmap_base = bitmap_region.base;
+        let bitmap_ptr = (bitmap_base + physical_memory_offset) as *mut u64;
+        let bitmap = core::slice::from_raw_parts_mut(bitmap_ptr, word_count);
+
+        let bitmap_start = (bitmap_base / FRAME_SIZE) as usize;
+
+        // Start with everything marked as used, and then free the usable regions
+        bitmap.fill(u64::MAX);
+        let mut allocator = Self {
+            bitmap,
+            frame_count,
+            free_frames: 0,
+            next: 0,
+            memory_map,
+            bitmap_frames: bitmap_start..(bitmap_start + bitmap_frames as usize),
+        };
+        for region in usable_regions() {
+            let start = (region.base / FRAME_SIZE) as usize;
+            let end = ((region.base + region.len) / FRAME_SIZE) as usize;
+            for frame in start..end {
+                allocator.clear_bit(frame);
+            }
+        }
+
+        // Reserve the frames holding the bitmap, and the null frame just to be safe
+        for frame in allocator.bitmap_frames.clone() {
+            allocator.set_bit(frame);
+        }
+        allocator.set_bit(0);
+
+        allocator
+    }
+
+    /// Returns the amount of frames that can still be allocated.
+    pub fn free_frames(&self) -> usize {
+        self.free_frames
+    }
+
+    /// Returns the amount of frames tracked by this allocator, including frames in use.
+    pub fn total_frames(&self) -> usize {
+        self.frame_count
+    }
+
+    /// Returns whether `frame` could have been handed out by this allocator.
+    fn is_usable(&self, frame: usize) -> bool {
+        if frame == 0 || frame >= self.frame_count || self.bitmap_frames.contains(&frame) {
+            return false;
+        }
+        let addr = frame as u64 * FRAME_SIZE;
+        self.memory_map.memmap().iter()
+            .filter(|r| r.typ == LimineMemoryMapEntryType::Usable)
+            .any(|r| addr >= r.base && addr + FRAME_SIZE <= r.base + r.len)
+    }
+
+    fn is_set(&self, frame: usize) -> bool {
+        self.bitmap[frame / BITS_PER_WORD] & (1 << (frame % BITS_PER_WORD)) != 0
+    }
+
+    fn set_bit(&mut self, frame: usize) {
+        if !self.is_set(frame) {
+            self.bitmap[frame / BITS_PER_WORD] |= 1 << (frame % BITS_PER_WORD);
+            self.free_frames -= 1;
+        }
+    }
+
+    fn clear_bit(&mut self, frame: usize) {
+        if self.is_set(frame) {
+            self.bitmap[frame / BITS_PER_WORD] &= !(1 << (frame % BITS_PER_WORD));
+            self.free_frames += 1;
+        }
+    }
+}
+
+unsafe impl FrameAllocator<Size4KiB> for BitmapFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        if self.free_frames == 0 {
+            return None;
+        }
+
+        let word_idx = (self.next..self.bitmap.len()).find(|i| self.bitmap[*i] != u64::MAX)?;
+        let frame = word_idx * BITS_PER_WORD + self.bitmap[word_idx].trailing_ones() as usize;
+        if frame >= self.frame_count {
+            return None;
+        }
+        self.set_bit(frame);
+        self.next = word_idx;
+
+        Some(PhysFrame::containing_address(PhysAddr::new(frame as u64 * FRAME_SIZE)))
+    }
+}
+
+impl FrameDeallocator<Size4KiB> for BitmapFrameAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame) {
+        let frame = (frame.start_address().as_u64() / FRAME_SIZE) as usize;
+        // Reserved, ACPI or MMIO frames were never handed out, and must never be
+        let usable = self.is_usable(frame);
+        debug_assert!(usable, "Freeing physical frame {:#x}, which is not usable memory!", frame as u64 * FRAME_SIZE);
+        if !usable {
+            return;
+        }
+        if !self.is_set(frame) {
+            panic!("Double free of physical frame {:#x}!", frame as u64 * FRAME_SIZE);
+        }
+        self.clear_bit(frame);
+        self.next = self.next.min(frame / BITS_PER_WORD);
+    }
+}
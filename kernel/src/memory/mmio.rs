@@ -0,0 +1,51 @@
+//! Mappings for memory mapped device registers.
+//! The higher half direct map can't be used for these, as the bootloader maps it with
+//! huge pages and write-back caching. Instead, every device gets its own uncached
+//! mapping in a dedicated virtual region.
+
+use x86_64::{PhysAddr, VirtAddr};
+use x86_64::structures::paging::{Page, PageTableFlags, PhysFrame};
+use spin::Mutex;
+
+use super::regions::{reserve_region, VirtualRegion, RegionKind};
+
+pub const MMIO_REGION_START: u64 = 0xFFFF_D000_0000_0000;
+pub const MMIO_REGION_SIZE:  u64 = 1024 * 1024 * 1024;
+
+const PAGE_SIZE: u64 = 4096;
+
+/// Mappings are never released, so the next free address only ever grows.
+static NEXT: Mutex<VirtAddr> = Mutex::new(VirtAddr::new_truncate(MMIO_REGION_START));
+
+pub fn init() {
+    reserve_region(VirtualRegion::new("mmio", VirtAddr::new(MMIO_REGION_START), MMIO_REGION_SIZE, RegionKind::Mmio)
+        .with_flags(PageTableFlags::NO_CACHE | PageTableFlags::WRITE_THROUGH))
+        .expect("Failed to reserve MMIO region!");
+}
+
+/// Maps `size` bytes of device registers starting at `phys`, uncached, and returns
+/// the virtual address of `phys`. Panics if the region runs out, or the mapping fails,
+/// as a driver can't do anything without its registers.
+pub fn map_mmio(phys: PhysAddr, size: u64) -> VirtAddr {
+    let first_frame = PhysFrame::containing_address(phys);
+    let last_frame = PhysFrame::containing_address(phys + size.max(1) - 1u64);
+    let frames = PhysFrame::range_inclusive(first_frame, last_frame);
+
+    let start = {
+        let mut next = NEXT.lock();
+        let start = *next;
+        let end = start + frames.count() as u64 * PAGE_SIZE;
+        assert!(end.as_u64() <= MMIO_REGION_START + MMIO_REGION_SIZE, "MMIO region is full!");
+        *next = end;
+        start
+    };
+
+    let flags = PageTableFlags::NO_CACHE | PageTableFlags::WRITE_THROUGH;
+    for (i, frame) in frames.enumerate() {
+        let page = Page::containing_address(start + i as u64 * PAGE_SIZE);
+        if let Err(e) = unsafe { super::map_page_to_frame(page, frame, Some(flags)) } {
+            panic!("Failed to map MMIO at {:?}: {:?}", frame.start_address(), e);
+        }
+    }
+    start + (phys - first_frame.start_address())
+}
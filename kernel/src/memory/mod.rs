@@ -0,0 +1,173 @@
+use limine::*;
+use x86_64::VirtAddr;
+use x86_64::structures::paging::{Page, PageTable, OffsetPageTable, page::PageRange};
+
+mod frame_allocator;
+pub use frame_allocator::BitmapFrameAllocator;
+pub use mmio::map_mmio;
+
+pub mod mmio;
+pub mod regions;
+pub mod stack;
+
+static mut MEMORY_MAPPER: Option<OffsetPageTable> = None;
+static mut FRAME_ALLOCATOR: Option<BitmapFrameAllocator> = None;
+
+static HHDM_REQUEST: LimineHhdmRequest = LimineHhdmRequest::new(0);
+static MEMMAP_REQUEST: LimineMemmapRequest = LimineMemmapRequest::new(0);
+
+static mut HHDM_OFFSET: u64 = 0;
+
+pub fn init() {
+    if let Some(hhdm_response) = HHDM_REQUEST.get_response().get() {
+        if let Some(memmap_response) = MEMMAP_REQUEST.get_response().get() {
+            unsafe { HHDM_OFFSET = hhdm_response.offset; }
+            let memory_mapper = unsafe { init_mapper(VirtAddr::new(HHDM_OFFSET)) };
+            let frame_allocator = unsafe { BitmapFrameAllocator::init(memmap_response, HHDM_OFFSET) };
+            debug!("Physical frames free: {}/{}", frame_allocator.free_frames(), frame_allocator.total_frames());
+            unsafe {
+                MEMORY_MAPPER = Some(memory_mapper);
+                FRAME_ALLOCATOR = Some(frame_allocator);
+            }
+            stack::init();
+            mmio::init();
+        } else {
+            panic!("Failed to get memory map information!");
+        }
+    } else {
+        panic!("Failed to get HHDM information!");
+    }
+}
+
+pub fn hhdm_offset() -> u64 {
+    unsafe { HHDM_OFFSET }
+}
+
+pub fn memory_mapper() -> &'static mut OffsetPageTable<'static> {
+    unsafe { MEMORY_MAPPER.as_mut().unwrap() }
+}
+
+pub fn frame_allocator() -> &'static mut BitmapFrameAllocator {
+    unsafe { FRAME_ALLOCATOR.as_mut().unwrap() }
+}
+
+/// Returns a mutable reference to the active level 4 table.
+///
+/// This function is unsafe because the caller must guarantee that the
+/// complete physical memory is mapped to virtual memory at the passed
+/// `physical_memory_offset`. Also, this function must be only called once
+/// to avoid aliasing `&mut` references (which is undefined behavior).
+unsafe fn active_level_4_table(physical_memory_offset: VirtAddr)
+    -> &'static mut PageTable
+{
+    use x86_64::registers::control::Cr3;
+
+    let (level_4_table_frame, _) = Cr3::read();
+
+    let phys = level_4_table_frame.start_address();
+    let virt = physical_memory_offset + phys.as_u64();
+    let page_table_ptr: *mut PageTable = virt.as_mut_ptr();
+
+    &mut *page_table_ptr // unsafe
+}
+
+/// Initialize a new OffsetPageTable.
+///
+/// This function is unsafe because the caller must guarantee that the
+/// complete physical memory is mapped to virtual memory at the passed
+/// `physical_memory_offset`. Also, this function must be only called once
+/// to avoid aliasing `&mut` references (which is undefined behavior).
+pub unsafe fn init_mapper(physical_memory_offset: VirtAddr) -> OffsetPageTable<'static> {
+    let level_4_table = active_level_4_table(physical_memory_offset);
+    OffsetPageTable::new(level_4_table, physical_memory_offset)
+}
+
+use x86_64::structures::paging::{FrameAllocator, FrameDeallocator, Size4KiB, PhysFrame};
+use x86_64::structures::paging::mapper::MapToError;
+use x86_64::structures::paging::Mapper;
+use x86_64::structures::paging::PageTableFlags;
+
+/// Guards the page tables and the frame allocator. Page faults can map pages
+/// on any core, so every modification has to go through this lock.
+static MAPPING_LOCK: spin::Mutex<()> = spin::Mutex::new(());
+
+fn with_mapping_lock<T, F: FnOnce() -> T>(f: F) -> T {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        // The core holding the lock might be waiting for us to flush our TLB,
+        // and we can't take the interrupt for that, so do it while waiting
+        let _lock = loop {
+            if let Some(lock) = MAPPING_LOCK.try_lock() {
+                break lock;
+            }
+            crate::ipi::process_tlb_shootdown();
+            core::hint::spin_loop();
+        };
+        f()
+    })
+}
+
+/// Returns true if the page is currently mapped to a frame.
+pub fn is_mapped(page: Page) -> bool {
+    with_mapping_lock(|| memory_mapper().translate_page(page).is_ok())
+}
+
+/// Like [`is_mapped`], but without taking the mapping lock, for the panic path,
+/// where a stopped core might be holding it forever. Returns false before the memory manager is set up.
+pub fn is_mapped_unlocked(page: Page) -> bool {
+    unsafe { MEMORY_MAPPER.as_ref() }.map(|mapper| mapper.translate_page(page).is_ok()).unwrap_or(false)
+}
+
+/// Unmaps a page and gives the frame backing it back to the frame allocator.
+/// Only use this for pages that were mapped with [`map_page`], as the frame
+/// is assumed to be owned by the frame allocator.
+pub fn unmap_page(page: Page) {
+    unmap_pages(Page::range(page, page + 1));
+}
+
+/// Unmaps every mapped page in the range, and gives the frames backing them back to the frame allocator.
+/// Other cores might still have the pages in their TLB, so they are flushed everywhere with a
+/// single shootdown. The lock is held until then, so the frames can't be reused before that.
+/// Like [`unmap_page`], only use this for pages that were mapped with [`map_page`].
+pub fn unmap_pages(pages: PageRange) {
+    with_mapping_lock(|| {
+        for page in pages {
+            if let Ok((frame, flush)) = memory_mapper().unmap(page) {
+                // Flushed everywhere by the shootdown below
+                flush.ignore();
+                unsafe { frame_allocator().deallocate_frame(frame); }
+            }
+        }
+        crate::ipi::tlb_shootdown(pages.start.start_address(), pages.count() as u64);
+    });
+}
+
+/// Maps a page to a physical frame. Currently marked as unsafe, because I'm unsure of its safety.
+/// It shouldn't remap in-use frames, but if it happens, please let me know in a Github issue.
+pub unsafe fn map_page(page: Page, extra_flags: Option<PageTableFlags>) -> Result<(), MapToError<Size4KiB>> {
+    with_mapping_lock(|| {
+        let frame = match frame_allocator().allocate_frame() {
+            Some(frame) => frame,
+            None => return Err(MapToError::FrameAllocationFailed),
+        };
+
+        let result = map_page_to_frame_locked(page, frame, extra_flags);
+        if result.is_err() {
+            frame_allocator().deallocate_frame(frame);
+        }
+        result
+    })
+}
+
+pub unsafe fn map_page_to_frame(page: Page, frame: PhysFrame, extra_flags: Option<PageTableFlags>) -> Result<(), MapToError<Size4KiB>> {
+    with_mapping_lock(|| map_page_to_frame_locked(page, frame, extra_flags))
+}
+
+unsafe fn map_page_to_frame_locked(page: Page, frame: PhysFrame, extra_flags: Option<PageTableFlags>) -> Result<(), MapToError<Size4KiB>> {
+    let mut flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    if let Some(extra_flags) = extra_flags {
+        flags |= extra_flags;
+    }
+
+    memory_mapper().map_to(page, frame, flags, frame_allocator())?.flush();
+    Ok(())
+}
@@ -0,0 +1,109 @@
+//! A registry of reserved virtual memory regions.
+//! The page fault handler uses this to figure out whether a fault on a
+//! non-present page should be resolved by mapping a fresh frame, or whether
+//! it is an actual bug.
+
+use x86_64::VirtAddr;
+use x86_64::structures::paging::PageTableFlags;
+use spin::Mutex;
+
+const MAX_REGIONS: usize = 32;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RegionKind {
+    /// The kernel heap. Pages are mapped on first access.
+    Heap,
+    /// Kernel stacks. These are mapped up front, and faults in here are never resolved.
+    KernelStack,
+    /// Memory reserved for a running program. Pages are mapped on first access.
+    Program,
+    /// Device registers, mapped up front by `memory::mmio`. Faults in here are never resolved.
+    Mmio,
+}
+
+impl RegionKind {
+    /// Returns true if a non-present fault inside a region of this kind
+    /// should be resolved by mapping a fresh frame.
+    pub fn is_demand_paged(&self) -> bool {
+        match self {
+            Self::Heap | Self::Program => true,
+            Self::KernelStack | Self::Mmio => false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct VirtualRegion {
+    pub name: &'static str,
+    pub start: VirtAddr,
+    pub size: u64,
+    pub kind: RegionKind,
+    /// Extra flags used when mapping pages in this region.
+    pub flags: PageTableFlags,
+}
+
+impl VirtualRegion {
+    pub fn new(name: &'static str, start: VirtAddr, size: u64, kind: RegionKind) -> Self {
+        Self {
+            name,
+            start,
+            size,
+            kind,
+            flags: PageTableFlags::empty(),
+        }
+    }
+
+    pub fn with_flags(mut self, flags: PageTableFlags) -> Self {
+        self.flags = flags;
+        self
+    }
+
+    pub fn end(&self) -> VirtAddr {
+        self.start + self.size
+    }
+
+    pub fn contains(&self, addr: VirtAddr) -> bool {
+        addr >= self.start && addr < self.end()
+    }
+
+    fn overlaps(&self, other: &VirtualRegion) -> bool {
+        self.start < other.end() && other.start < self.end()
+    }
+}
+
+#[derive(Debug)]
+pub enum RegionError {
+    Overlapping(&'static str),
+    RegistryFull,
+    NotFound,
+}
+
+// This is a fixed size array instead of a heap allocated collection, because the
+// heap itself depends on this registry to grow.
+static REGIONS: Mutex<[Option<VirtualRegion>; MAX_REGIONS]> = Mutex::new([None; MAX_REGIONS]);
+
+/// Reserves a region of virtual memory. Fails if it overlaps with an already reserved region.
+pub fn reserve_region(region: VirtualRegion) -> Result<(), RegionError> {
+    let mut lock = REGIONS.lock();
+    if let Some(existing) = lock.iter().flatten().find(|existing| existing.overlaps(&region)) {
+        return Err(RegionError::Overlapping(existing.name));
+    }
+    let slot = lock.iter_mut().find(|slot| slot.is_none()).ok_or(RegionError::RegistryFull)?;
+    *slot = Some(region);
+    // Logging might allocate, and fault in a heap page, which needs the lock to find the heap region
+    drop(lock);
+    trace!("Reserved region `{}` at {:?} ({} bytes)", region.name, region.start, region.size);
+    Ok(())
+}
+
+/// Releases the region starting at `start`. This does not unmap any pages.
+pub fn release_region(start: VirtAddr) -> Result<VirtualRegion, RegionError> {
+    let mut lock = REGIONS.lock();
+    let slot = lock.iter_mut().find(|slot| slot.map(|r| r.start == start).unwrap_or(false)).ok_or(RegionError::NotFound)?;
+    Ok(slot.take().unwrap())
+}
+
+/// Returns the region containing `addr`, if there is one.
+pub fn find_region(addr: VirtAddr) -> Option<VirtualRegion> {
+    REGIONS.lock().iter().flatten().find(|region| region.contains(addr)).copied()
+}
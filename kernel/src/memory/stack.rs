@@ -0,0 +1,131 @@
+//! Allocator for kernel stacks.
+//! Every stack lives in a dedicated virtual region, and has an unmapped guard
+//! page directly below it. Running off the end of a stack will therefore always
+//! fault, which the double fault handler reports as a stack overflow.
+
+use x86_64::VirtAddr;
+use x86_64::structures::paging::Page;
+use spin::Mutex;
+
+use super::regions::{reserve_region, VirtualRegion, RegionKind};
+
+pub const KERNEL_STACK_REGION_START: u64 = 0xFFFF_E000_0000_0000;
+pub const KERNEL_STACK_REGION_SIZE:  u64 = 64 * 1024 * 1024 * 1024;
+
+/// Size of the ring 0 stack of every core.
+pub const KERNEL_STACK_SIZE: usize = 16 * 4096;
+/// Size of every interrupt stack (double fault, NMI).
+pub const IST_STACK_SIZE: usize = 4 * 4096;
+
+const PAGE_SIZE: u64 = 4096;
+const MAX_STACKS: usize = 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StackKind {
+    Kernel,
+    DoubleFault,
+    Nmi,
+    /// The stack of a kernel thread, see `kernel_common::task_system::thread`.
+    Thread,
+}
+
+/// A stack allocated by the stack allocator.
+/// The stack grows down from `top` to `bottom`, and `bottom - 4096` is the guard page.
+#[derive(Debug, Clone, Copy)]
+pub struct KernelStack {
+    pub top: VirtAddr,
+    pub bottom: VirtAddr,
+    pub core: usize,
+    pub kind: StackKind,
+}
+
+impl KernelStack {
+    pub fn size(&self) -> u64 {
+        self.top - self.bottom
+    }
+
+    pub fn guard_page(&self) -> Page {
+        Page::containing_address(self.bottom - PAGE_SIZE)
+    }
+}
+
+#[derive(Clone, Copy)]
+struct StackSlot {
+    stack: KernelStack,
+    in_use: bool,
+}
+
+struct StackAllocator {
+    next: VirtAddr,
+    // Fixed size, because the interrupt stacks have to be allocated before the heap exists
+    slots: [Option<StackSlot>; MAX_STACKS],
+}
+
+static STACK_ALLOCATOR: Mutex<StackAllocator> = Mutex::new(StackAllocator {
+    next: VirtAddr::new_truncate(KERNEL_STACK_REGION_START),
+    slots: [None; MAX_STACKS],
+});
+
+pub fn init() {
+    reserve_region(VirtualRegion::new("kernel_stacks", VirtAddr::new(KERNEL_STACK_REGION_START), KERNEL_STACK_REGION_SIZE, RegionKind::KernelStack))
+        .expect("Failed to reserve kernel stack region!");
+}
+
+/// Allocates a stack of at least `size` bytes and maps it.
+/// The stack is fully mapped up front, as the stack is also used to handle page faults.
+/// Returns None if the stack region or physical memory ran out.
+pub fn allocate_stack(size: usize, core: usize, kind: StackKind) -> Option<KernelStack> {
+    let size = (size as u64 + PAGE_SIZE - 1) & !(PAGE_SIZE - 1);
+
+    let mut lock = STACK_ALLOCATOR.lock();
+    // Prefer reusing a freed stack of the same size, so the region does not run out
+    let reused = lock.slots.iter_mut().flatten().find(|slot| !slot.in_use && slot.stack.size() == size);
+    let stack = if let Some(slot) = reused {
+        slot.in_use = true;
+        slot.stack.core = core;
+        slot.stack.kind = kind;
+        slot.stack
+    } else {
+        let bottom = lock.next + PAGE_SIZE;
+        let top = bottom + size;
+        if top.as_u64() > KERNEL_STACK_REGION_START + KERNEL_STACK_REGION_SIZE {
+            return None;
+        }
+        let stack = KernelStack { top, bottom, core, kind };
+        let slot = lock.slots.iter_mut().find(|slot| slot.is_none())?;
+        *slot = Some(StackSlot { stack, in_use: true });
+        lock.next = top;
+        stack
+    };
+    drop(lock);
+
+    let pages = Page::range(Page::containing_address(stack.bottom), Page::containing_address(stack.top));
+    for page in pages {
+        if unsafe { super::map_page(page, None) }.is_err() {
+            unsafe { free_stack(stack); }
+            return None;
+        }
+    }
+
+    Some(stack)
+}
+
+/// Unmaps a stack and marks it as free, so its virtual memory can be reused.
+///
+/// This function is unsafe because the caller must guarantee that nothing is using the stack anymore.
+pub unsafe fn free_stack(stack: KernelStack) {
+    super::unmap_pages(Page::range(Page::containing_address(stack.bottom), Page::containing_address(stack.top)));
+
+    let mut lock = STACK_ALLOCATOR.lock();
+    if let Some(slot) = lock.slots.iter_mut().flatten().find(|slot| slot.stack.bottom == stack.bottom) {
+        slot.in_use = false;
+    }
+}
+
+/// Returns the stack whose guard page contains `addr`, if there is one.
+pub fn stack_for_guard_page(addr: VirtAddr) -> Option<KernelStack> {
+    let page = Page::containing_address(addr);
+    STACK_ALLOCATOR.lock().slots.iter().flatten()
+        .find(|slot| slot.in_use && slot.stack.guard_page() == page)
+        .map(|slot| slot.stack)
+}
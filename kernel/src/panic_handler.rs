@@ -1,34 +1,129 @@
+use core::fmt::{self, Write};
 use core::panic::PanicInfo;
-use crate::{framebuffer::{self, TextSize}, util};
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use kernel_common::percpu;
+use kernel_common::task_system::task::try_with_task;
+
+use crate::{backtrace::Backtrace, framebuffer::{self, TextSize}, ipi, serial::SerialWriter, util};
+
+/// The id of the core that is panicking plus one, or 0 if no core panicked yet.
+static PANICKING: AtomicUsize = AtomicUsize::new(0);
+/// Set once the panicking core panicked again, while handling its first panic.
+static RECURSED: AtomicBool = AtomicBool::new(false);
+/// The report is formatted in here for the framebuffer. It is too large for the stack,
+/// as a panic might happen on a small interrupt stack.
+/// Only ever used by the first core to panic, and never again once that panicked recursively.
+static mut REPORT_BUFFER: [u8; 8192] = [0; 8192];
 
 /// This function is called on panic.
-/// It uses the framebuffer to show a nice looking panic graphic.
-/// If the framebuffer fails to initialize, it'll panic, and this
-/// will be broken!
+/// Only the first core to panic handles it. It freezes every other core, and writes a report
+/// of the panic and the state of every core to the serial port and the framebuffer.
+/// Nothing on the way takes a lock that might be held already, as the panicking core
+/// or a frozen one might have been holding it.
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
+    x86_64::instructions::interrupts::disable();
+    let backtrace = Backtrace::capture();
+
+    // Early during boot there is no per-CPU data yet, but only the bootstrap core is running then
+    let core_id = percpu::try_this_cpu().map(|cpu| cpu.core_id()).unwrap_or(0);
+    if let Err(panicking) = PANICKING.compare_exchange(0, core_id + 1, Ordering::SeqCst, Ordering::SeqCst) {
+        if panicking == core_id + 1 {
+            recursive_panic(core_id, info);
+        }
+        // Another core is already handling its panic, and is stopping this one anyway
+        ipi::halt_stopped();
+    }
+
+    // Stop the other cores first, so they can't make things worse or draw over the panic screen
+    if percpu::try_this_cpu().is_some() {
+        ipi::stop_all_cores();
+    }
+
+    let _ = write_report(&mut SerialWriter, core_id, info, &backtrace);
+
+    let report = {
+        // Safety: only the first core to panic gets here, and only once
+        let mut writer = util::WriteTo::new(unsafe { &mut *core::ptr::addr_of_mut!(REPORT_BUFFER) });
+        match write_report(&mut writer, core_id, info, &backtrace) {
+            Ok(()) => writer.as_str().unwrap_or("FMT FAILED\n"),
+            Err(_) => "FMT FAILED\n",
+        }
+    };
+    draw_report(report);
+
+    ipi::halt_stopped();
+}
+
+/// Handles a panic that happened while handling a panic, e.g. in the framebuffer code.
+/// Only the serial port is used, as whatever panicked can't be trusted anymore.
+fn recursive_panic(core_id: usize, info: &PanicInfo) -> ! {
+    if !RECURSED.swap(true, Ordering::SeqCst) {
+        let _ = write!(SerialWriter, "\nRECURSIVE PANIC on core {}: {}\n", core_id, info);
+    }
+    ipi::halt_stopped();
+}
+
+fn write_report(w: &mut impl Write, core_id: usize, info: &PanicInfo, backtrace: &Backtrace) -> fmt::Result {
+    write!(w, "\nPANIC on core {}", core_id)?;
+    let task = percpu::try_this_cpu().and_then(|cpu| cpu.current_task());
+    if let Some(task) = task {
+        write!(w, " in task {}", task)?;
+        // The task list might be locked by a frozen core
+        try_with_task(task, |task| match task.name() {
+            Some(name) => write!(w, " ({})", name),
+            None => Ok(()),
+        }).unwrap_or(Ok(()))?;
+    }
+    write!(w, "\n{}\n\n{}\n", info, backtrace)?;
+
+    for cpu in percpu::cpus() {
+        write!(w, "core {:>2}: ", cpu.core_id())?;
+        match cpu.last_rip() {
+            Some(rip) => write!(w, "rip {:#018x}", rip)?,
+            None => write!(w, "rip unknown")?,
+        }
+        if let Some(task) = cpu.current_task() {
+            write!(w, ", task {}", task)?;
+        }
+        let status = if cpu.core_id() == core_id {
+            "panicked"
+        } else if cpu.lapic_id().is_none() {
+            "offline"
+        } else if ipi::is_stopped(cpu.core_id()) {
+            "stopped"
+        } else {
+            "not responding"
+        };
+        writeln!(w, " [{}]", status)?;
+    }
+    Ok(())
+}
+
+fn draw_report(report: &str) {
     let bg_col = [39, 92, 53];
     let fg_col = [255; 3];
 
-    let mut buf = [0u8; 4096];
-
-    {
-        let mut fb = framebuffer::fb_mut();
-        let width = fb.width();
-        let height = fb.height();
-        fb.set_clear_color(bg_col);
-        fb.clear();
-        let mut panic_area = framebuffer::Rect::new(0, height / 4, width, height);
-        let (panic_width, _) = fb.print(&panic_area, bg_col, TextSize::Big, "PANIC!");
-        fb.clear();
-        panic_area.x0 = width / 2 - panic_width / 2;
-        let (_, delta_height) = fb.print(&panic_area, fg_col, TextSize::Big, "PANIC!\n");
-        let mut text_area = framebuffer::Rect::new(width / 6, height / 4 + delta_height + 8, width * 5 / 6, height * 3 / 4);
-        let (_, delta_height) = fb.print(&text_area, fg_col, TextSize::Normal, util::show(&mut buf, format_args!("{}\n", info)).unwrap_or("FMT FAILED\n"));
-        text_area.y1 = text_area.y1.max(text_area.y0 + delta_height);
-        let outline_area = framebuffer::Rect::new(text_area.x0 - 2, text_area.y0 - 2, text_area.x1 + 2, text_area.y1 + 2);
-        fb.outline_double(&outline_area, fg_col);
+    // Safety: every other core is stopped, or at least got told to, and this core
+    // won't return to whatever held the lock
+    let mut fb = unsafe { framebuffer::fb_emergency() };
+    let width = fb.width();
+    let height = fb.height();
+    if width == 0 || height == 0 {
+        // Panicked before the framebuffer was set up, so the serial port has to do
+        return;
     }
-
-    crate::hlt_loop();
+    fb.set_clear_color(bg_col);
+    fb.clear();
+    let mut panic_area = framebuffer::Rect::new(0, height / 8, width, height);
+    let (panic_width, _) = fb.print(&panic_area, bg_col, TextSize::Big, "PANIC!");
+    fb.clear();
+    panic_area.x0 = width / 2 - panic_width / 2;
+    let (_, delta_height) = fb.print(&panic_area, fg_col, TextSize::Big, "PANIC!\n");
+    let mut text_area = framebuffer::Rect::new(width / 8, height / 8 + delta_height + 8, width * 7 / 8, height * 7 / 8);
+    let (_, delta_height) = fb.print(&text_area, fg_col, TextSize::Small, report.trim_start());
+    text_area.y1 = text_area.y1.max(text_area.y0 + delta_height);
+    let outline_area = framebuffer::Rect::new(text_area.x0 - 2, text_area.y0 - 2, text_area.x1 + 2, text_area.y1 + 2);
+    fb.outline_double(&outline_area, fg_col);
 }
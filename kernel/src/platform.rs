@@ -0,0 +1,74 @@
+//! The kernel's implementation of the platform hooks used by `kernel_common`.
+
+use core::fmt::{self, Write};
+use core::ops::Range;
+use x86_64::VirtAddr;
+use kernel_common::irq::{IrqSource, IrqError, IrqTrigger};
+use kernel_common::platform::Platform;
+
+use crate::ioapic::{self, Trigger, Active};
+use crate::memory::stack::{self, KernelStack, StackKind};
+
+pub struct KernelPlatform;
+
+pub static PLATFORM: KernelPlatform = KernelPlatform;
+
+impl KernelPlatform {
+    fn gsi(source: IrqSource) -> (u32, Trigger, Active) {
+        match source {
+            IrqSource::Isa(irq) => ioapic::isa_irq_to_gsi(irq),
+            // PCI interrupts are level triggered and active low
+            IrqSource::Gsi(gsi) => (gsi, Trigger::Level, Active::Low),
+        }
+    }
+}
+
+impl Platform for KernelPlatform {
+    fn wake_core(&self, core_id: usize) {
+        crate::ipi::wake_core(core_id);
+    }
+
+    fn route_irq(&self, source: IrqSource, vector: u8, core_id: usize) -> Result<IrqTrigger, IrqError> {
+        let (gsi, trigger, active) = Self::gsi(source);
+        ioapic::route_gsi(gsi, vector, core_id, trigger, active).map_err(|e| {
+            warn!("Failed to route {:?}: {:?}", source, e);
+            IrqError::RoutingFailed
+        })?;
+        Ok(match trigger {
+            Trigger::Edge => IrqTrigger::Edge,
+            Trigger::Level => IrqTrigger::Level,
+        })
+    }
+
+    fn resolve_irq(&self, source: IrqSource) -> IrqSource {
+        IrqSource::Gsi(Self::gsi(source).0)
+    }
+
+    fn mask_irq(&self, source: IrqSource) {
+        let (gsi, _, _) = Self::gsi(source);
+        let _ = ioapic::mask_gsi(gsi);
+    }
+
+    fn unmask_irq(&self, source: IrqSource) {
+        let (gsi, _, _) = Self::gsi(source);
+        let _ = ioapic::unmask_gsi(gsi);
+    }
+
+    fn allocate_stack(&self, size: usize) -> Option<Range<usize>> {
+        let stack = stack::allocate_stack(size, crate::apic::current_core(), StackKind::Thread)?;
+        Some(stack.bottom.as_u64() as usize..stack.top.as_u64() as usize)
+    }
+
+    unsafe fn free_stack(&self, memory: Range<usize>) {
+        stack::free_stack(KernelStack {
+            top: VirtAddr::new(memory.end as u64),
+            bottom: VirtAddr::new(memory.start as u64),
+            core: crate::apic::current_core(),
+            kind: StackKind::Thread,
+        });
+    }
+
+    fn emergency_write(&self, args: fmt::Arguments) {
+        let _ = crate::serial::SerialWriter.write_fmt(args);
+    }
+}
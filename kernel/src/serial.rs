@@ -0,0 +1,70 @@
+//! A minimal driver for the COM1 serial port.
+//! It is only used as a last resort output while panicking, so it doesn't take any locks,
+//! and keeps working no matter what state the rest of the kernel is in.
+
+use core::fmt;
+use x86_64::instructions::port::Port;
+
+const COM1: u16 = 0x3F8;
+
+const REG_DATA: u16 = 0;
+const REG_INTERRUPT_ENABLE: u16 = 1;
+const REG_FIFO_CONTROL: u16 = 2;
+const REG_LINE_CONTROL: u16 = 3;
+const REG_MODEM_CONTROL: u16 = 4;
+const REG_LINE_STATUS: u16 = 5;
+
+/// Line control: the data registers hold the baud rate divisor instead.
+const LINE_DLAB: u8 = 1 << 7;
+/// Line control: 8 data bits, no parity, one stop bit.
+const LINE_8N1: u8 = 0b11;
+/// Line status: the transmitter can take another byte.
+const LINE_TRANSMIT_EMPTY: u8 = 1 << 5;
+/// 115200 / 3 = 38400 baud.
+const BAUD_DIVISOR: u16 = 3;
+/// Gives up on a byte after polling this often, so a missing port can't hang the panic handler.
+const MAX_POLLS: usize = 100_000;
+
+fn port(register: u16) -> Port<u8> {
+    Port::new(COM1 + register)
+}
+
+/// Sets up COM1 for 38400 baud 8N1.
+pub fn init() {
+    unsafe {
+        port(REG_INTERRUPT_ENABLE).write(0);
+        port(REG_LINE_CONTROL).write(LINE_DLAB);
+        port(REG_DATA).write(BAUD_DIVISOR as u8);
+        port(REG_INTERRUPT_ENABLE).write((BAUD_DIVISOR >> 8) as u8);
+        port(REG_LINE_CONTROL).write(LINE_8N1);
+        // Enable and clear the FIFOs
+        port(REG_FIFO_CONTROL).write(0xC7);
+        // Data terminal ready and request to send
+        port(REG_MODEM_CONTROL).write(0x03);
+    }
+}
+
+fn write_byte(byte: u8) {
+    for _ in 0..MAX_POLLS {
+        if unsafe { port(REG_LINE_STATUS).read() } & LINE_TRANSMIT_EMPTY != 0 {
+            break;
+        }
+        core::hint::spin_loop();
+    }
+    unsafe { port(REG_DATA).write(byte); }
+}
+
+/// Writes to COM1. Has no state, so any amount of them can exist at once.
+pub struct SerialWriter;
+
+impl fmt::Write for SerialWriter {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for byte in s.bytes() {
+            if byte == b'\n' {
+                write_byte(b'\r');
+            }
+            write_byte(byte);
+        }
+        Ok(())
+    }
+}
@@ -10,6 +10,10 @@ use kernel_common::services::{service_manager, ArcMessage};
 use kernel_common::driver_common::DriverCommand;
 use kernel_common::Promise;
 
+const CLOCKID_REALTIME: i32 = 0;
+const CLOCKID_MONOTONIC: i32 = 1;
+const ERRNO_INVAL: i32 = 28;
+
 pub struct Abi;
 
 impl Abi {
@@ -70,6 +74,17 @@ impl AbiTrait for Abi {
         0 // 0 = Success in ErrNo
     }
 
+    // Writes the time in nanoseconds to offset0.
+    fn clock_time_get(&self, mut context: Context, id: i32, _precision: i64, offset0: i32) -> i32 {
+        let nanos = match id {
+            CLOCKID_REALTIME => kernel_common::wall_clock::now_unix_nanos(),
+            CLOCKID_MONOTONIC => kernel_common::time::Instant::now().as_nanos(),
+            _ => return ERRNO_INVAL,
+        };
+        context.write_memory(offset0 as usize, &u64::to_le_bytes(nanos));
+        0
+    }
+
     fn environ_sizes_get(&self, mut context: Context, offset0: i32, offset1: i32) -> i32 {
         context.write_memory(offset0 as usize, &[0; 1]);
         context.write_memory(offset1 as usize, &[0; 1]);
@@ -10,7 +10,9 @@ use alloc::boxed::Box;
 
 use kernel_common::task_system::{
     spawner::Spawner,
-    scheduler::{scheduler_spawn_task, SchedulerService},
+    scheduler::SchedulerService,
+    builder::TaskBuilder,
+    task::Priority,
 };
 use kernel_common::services::service_manager;
 use kernel_common::requests::*;
@@ -29,7 +31,7 @@ pub struct KernelBuilder {
 }
 
 impl KernelBuilder {
-    pub fn new(spawner: Spawner, processor_id: usize) -> Self {
+    pub fn new(spawner: Spawner) -> Self {
         // let stack = fringe::OwnedStack::new(1 << 16);
         // let mut gen = unsafe { fringe::Generator::unsafe_new(stack, move |yielder, ()| {
         //     test_func();
@@ -38,8 +40,9 @@ impl KernelBuilder {
         // gen.resume(());
         // gen.resume(());
         Self {
+            // Tasks can be stolen by other cores, so this is where the spawner runs, not necessarily us
+            processor_id: spawner.core_id(),
             spawner,
-            processor_id,
             fb_init: false,
             log_init: false,
         }
@@ -100,14 +103,8 @@ pub struct Kernel {
 }
 
 impl Kernel {
-    pub fn builder(spawner: Spawner, processor_id: usize) -> KernelBuilder {
-        KernelBuilder::new(spawner, processor_id)
-    }
-
-    async fn spawn_async(&self, task: impl core::future::Future<Output = ()> + Send + 'static) {
-        // self.task_spawner.spawn_async(task).await;
-        let spawner_idx = scheduler_spawn_task(task);
-        info!("Spawning task on spawner {spawner_idx}...");
+    pub fn builder(spawner: Spawner) -> KernelBuilder {
+        KernelBuilder::new(spawner)
     }
 
     pub async fn run(self) {
@@ -115,8 +112,15 @@ impl Kernel {
             service_manager().add_service(Box::new(SchedulerService));
             service_manager().add_service(Box::new(services::StdoutSyslog));
             service_manager().add_service(Box::new(services::FileDescriptorManager::new()));
-            // self.spawn_async(yield_loop()).await;
-            self.spawn_async(run_wasm(WASM_TEST)).await;
+            service_manager().add_service(Box::new(kernel_common::wall_clock::WallClockService));
+            TaskBuilder::new()
+                .name("wall_clock_drift")
+                .priority(Priority::Background)
+                .spawn(kernel_common::wall_clock::drift_correction());
+            TaskBuilder::new()
+                .name("wasm_test")
+                .priority(Priority::User)
+                .spawn(run_wasm(WASM_TEST));
         }
     }
 }
@@ -0,0 +1,258 @@
+//! Bridge between hardware interrupts and async tasks.
+//! A driver requests an interrupt, and then awaits it from a task:
+//! ```ignore
+//! let irq = request_irq(IrqSource::Isa(1), 0)?;
+//! loop {
+//!     irq.wait().await;
+//!     handle_keyboard();
+//! }
+//! ```
+//! The interrupt handler itself only marks the vector as pending, so it never allocates or
+//! takes a lock that task code might hold. The executors pick up pending interrupts and wake
+//! the waiting tasks. Level triggered interrupts are masked until the driver waits again,
+//! as they would otherwise keep firing until the device is serviced.
+
+use core::future::Future;
+use core::pin::Pin;
+use core::task::{Context, Poll};
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use alloc::sync::Arc;
+
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+
+use crate::task_system::atomic_waker::AtomicWaker;
+
+/// First vector that can be handed out to drivers.
+pub const IRQ_VECTOR_BASE: u8 = 48;
+/// Amount of vectors that can be handed out to drivers.
+pub const IRQ_VECTOR_COUNT: usize = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IrqSource {
+    /// A legacy ISA IRQ, remapped according to the ACPI interrupt source overrides.
+    Isa(u8),
+    /// A global system interrupt. These are assumed to be PCI interrupts, so level triggered and active low.
+    Gsi(u32),
+}
+
+impl IrqSource {
+    // Encoded so it can be read from an interrupt handler without taking a lock. 0 means no source.
+    fn encode(&self) -> u64 {
+        match self {
+            IrqSource::Isa(irq) => (1 << 32) | *irq as u64,
+            IrqSource::Gsi(gsi) => (2 << 32) | *gsi as u64,
+        }
+    }
+
+    fn decode(value: u64) -> Option<Self> {
+        match value >> 32 {
+            1 => Some(IrqSource::Isa(value as u8)),
+            2 => Some(IrqSource::Gsi(value as u32)),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IrqTrigger {
+    Edge,
+    Level,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IrqError {
+    /// Every driver vector is in use.
+    NoFreeVector,
+    /// The vector is outside the driver range, or already registered.
+    InvalidVector(u8),
+    /// The platform could not route the interrupt.
+    RoutingFailed,
+}
+
+struct IrqState {
+    pending: AtomicUsize,
+    waker: AtomicWaker,
+}
+
+const SOURCE_INIT: AtomicU64 = AtomicU64::new(0);
+const MASKED_INIT: AtomicBool = AtomicBool::new(false);
+/// The resolved source of every level triggered vector, so it can be masked from the interrupt handler
+/// without looking anything up.
+static LEVEL_SOURCES: [AtomicU64; IRQ_VECTOR_COUNT] = [SOURCE_INIT; IRQ_VECTOR_COUNT];
+static MASKED: [AtomicBool; IRQ_VECTOR_COUNT] = [MASKED_INIT; IRQ_VECTOR_COUNT];
+
+/// Bitmap of vectors that fired, but haven't been handed to their task yet.
+static PENDING: AtomicU64 = AtomicU64::new(0);
+
+const STATE_INIT: Option<Arc<IrqState>> = None;
+/// Only ever locked with interrupts disabled, and never from an interrupt handler.
+static HANDLERS: Mutex<[Option<Arc<IrqState>>; IRQ_VECTOR_COUNT]> = Mutex::new([STATE_INIT; IRQ_VECTOR_COUNT]);
+
+/// A registered interrupt vector. Dropping it unregisters the vector, and masks its source.
+pub struct Irq {
+    vector: u8,
+    source: Option<IrqSource>,
+    state: Arc<IrqState>,
+}
+
+impl Irq {
+    pub fn vector(&self) -> u8 {
+        self.vector
+    }
+
+    pub fn source(&self) -> Option<IrqSource> {
+        self.source
+    }
+
+    /// Waits for the interrupt to fire. Resolves to the amount of times it fired since the last wait,
+    /// although interrupts that fire in quick succession may be merged.
+    /// If the interrupt was masked because it is level triggered, it gets unmasked again here.
+    pub fn wait(&self) -> IrqFuture<'_> {
+        let index = (self.vector - IRQ_VECTOR_BASE) as usize;
+        if MASKED[index].swap(false, Ordering::AcqRel) {
+            if let Some(source) = self.source {
+                crate::platform::platform().unmask_irq(source);
+            }
+        }
+        IrqFuture { irq: self }
+    }
+}
+
+impl Drop for Irq {
+    fn drop(&mut self) {
+        if let Some(source) = self.source {
+            crate::platform::platform().mask_irq(source);
+        }
+        let index = (self.vector - IRQ_VECTOR_BASE) as usize;
+        LEVEL_SOURCES[index].store(0, Ordering::Release);
+        MASKED[index].store(false, Ordering::Release);
+        without_interrupts(|| HANDLERS.lock()[index] = None);
+        // Clear any interrupt that fired in the meantime, so the next owner doesn't see it
+        PENDING.fetch_and(!(1 << index), Ordering::AcqRel);
+    }
+}
+
+pub struct IrqFuture<'a> {
+    irq: &'a Irq,
+}
+
+impl<'a> Future for IrqFuture<'a> {
+    type Output = usize;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<usize> {
+        let state = &self.irq.state;
+        let count = state.pending.swap(0, Ordering::AcqRel);
+        if count > 0 {
+            return Poll::Ready(count);
+        }
+
+        state.waker.register(cx.waker());
+        // The interrupt might have been delivered while registering
+        let count = state.pending.swap(0, Ordering::AcqRel);
+        if count > 0 {
+            Poll::Ready(count)
+        } else {
+            Poll::Pending
+        }
+    }
+}
+
+/// Allocates a free vector, and routes `source` to it on the given core.
+pub fn request_irq(source: IrqSource, core_id: usize) -> Result<Irq, IrqError> {
+    let (vector, state) = allocate_vector()?;
+    // Dropping this on failure gives the vector back
+    let mut irq = Irq { vector, source: None, state };
+
+    let trigger = crate::platform::platform().route_irq(source, vector, core_id)?;
+    if trigger == IrqTrigger::Level {
+        let resolved = crate::platform::platform().resolve_irq(source);
+        LEVEL_SOURCES[(vector - IRQ_VECTOR_BASE) as usize].store(resolved.encode(), Ordering::Release);
+    }
+    trace!("{:?} routed to vector {} on core {} ({:?})", source, vector, core_id, trigger);
+    irq.source = Some(source);
+    Ok(irq)
+}
+
+/// Registers a vector that is raised by something other than a routed interrupt, like an MSI.
+pub fn register_vector(vector: u8) -> Result<Irq, IrqError> {
+    if vector < IRQ_VECTOR_BASE || vector as usize >= IRQ_VECTOR_BASE as usize + IRQ_VECTOR_COUNT {
+        return Err(IrqError::InvalidVector(vector));
+    }
+    let index = (vector - IRQ_VECTOR_BASE) as usize;
+    let state = without_interrupts(|| {
+        let mut handlers = HANDLERS.lock();
+        if handlers[index].is_some() {
+            return None;
+        }
+        let state = Arc::new(IrqState::new());
+        handlers[index] = Some(state.clone());
+        Some(state)
+    }).ok_or(IrqError::InvalidVector(vector))?;
+    Ok(Irq { vector, source: None, state })
+}
+
+fn allocate_vector() -> Result<(u8, Arc<IrqState>), IrqError> {
+    without_interrupts(|| {
+        let mut handlers = HANDLERS.lock();
+        let index = handlers.iter().position(|handler| handler.is_none()).ok_or(IrqError::NoFreeVector)?;
+        let state = Arc::new(IrqState::new());
+        handlers[index] = Some(state.clone());
+        Ok((IRQ_VECTOR_BASE + index as u8, state))
+    })
+}
+
+impl IrqState {
+    fn new() -> Self {
+        Self {
+            pending: AtomicUsize::new(0),
+            waker: AtomicWaker::new(),
+        }
+    }
+}
+
+/// Called by the interrupt handler of every driver vector, before the end of interrupt.
+/// Only touches atomics, and masks level triggered sources through the platform hooks.
+pub fn dispatch(vector: u8) {
+    let index = vector.wrapping_sub(IRQ_VECTOR_BASE) as usize;
+    if index >= IRQ_VECTOR_COUNT {
+        return;
+    }
+    if let Some(source) = IrqSource::decode(LEVEL_SOURCES[index].load(Ordering::Acquire)) {
+        crate::platform::platform().mask_irq(source);
+        MASKED[index].store(true, Ordering::Release);
+    }
+    PENDING.fetch_or(1 << index, Ordering::AcqRel);
+    if let Some(cpu) = crate::percpu::try_this_cpu() {
+        cpu.stats.irqs.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Returns whether any interrupt fired that hasn't been handed to its task yet.
+pub fn has_pending() -> bool {
+    PENDING.load(Ordering::Acquire) != 0
+}
+
+/// Wakes the tasks waiting on interrupts that fired. Called by the executors.
+pub fn process_pending() {
+    if PENDING.load(Ordering::Acquire) == 0 {
+        return;
+    }
+    let pending = PENDING.swap(0, Ordering::AcqRel);
+
+    // Collect the wakers first, so tasks aren't woken with the lock held
+    let mut states: [Option<Arc<IrqState>>; IRQ_VECTOR_COUNT] = [STATE_INIT; IRQ_VECTOR_COUNT];
+    without_interrupts(|| {
+        let handlers = HANDLERS.lock();
+        for index in 0..IRQ_VECTOR_COUNT {
+            if pending & (1 << index) != 0 {
+                states[index] = handlers[index].clone();
+            }
+        }
+    });
+
+    for state in states.iter().flatten() {
+        state.pending.fetch_add(1, Ordering::AcqRel);
+        state.waker.wake();
+    }
+}
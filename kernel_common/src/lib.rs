@@ -16,6 +16,14 @@ pub mod wasm;
 pub mod services;
 pub mod driver_common;
 pub mod rtc;
+pub mod wall_clock;
+pub mod time;
+pub mod irq;
+pub mod platform;
+pub mod percpu;
+
+/// The maximum amount of cores we support. Any cores beyond this are simply not started.
+pub const MAX_CPUS: usize = 64;
 
 pub use spin::Mutex;
 pub use spin::MutexGuard;
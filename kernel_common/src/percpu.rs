@@ -0,0 +1,185 @@
+//! Per-CPU data. Every core has its own [`PerCpu`] block, which the `GS` base of that core points to,
+//! so the current core can always find its own data with a single memory access,
+//! even from interrupt handlers.
+//! The kernel sets it up with [`init`] as the very first thing on every core.
+
+use core::arch::asm;
+use core::sync::atomic::{AtomicBool, AtomicPtr, AtomicU32, AtomicU64, AtomicUsize, Ordering};
+use core::ptr;
+
+use conquer_once::spin::OnceCell;
+use x86_64::VirtAddr;
+use x86_64::registers::model_specific::GsBase;
+use x86_64::structures::tss::TaskStateSegment;
+
+use crate::MAX_CPUS;
+use crate::task_system::executor::TaskQueue;
+use crate::task_system::task::TaskId;
+
+/// Counters of what a core has been doing.
+pub struct CpuStats {
+    /// Timer interrupts received.
+    pub timer_ticks: AtomicU64,
+    /// Hardware interrupts received, see [`crate::irq`].
+    pub irqs: AtomicU64,
+    /// Times a task was polled.
+    pub polls: AtomicU64,
+    /// Times the executor halted because it ran out of work.
+    pub halts: AtomicU64,
+}
+
+impl CpuStats {
+    const fn new() -> Self {
+        Self {
+            timer_ticks: AtomicU64::new(0),
+            irqs: AtomicU64::new(0),
+            polls: AtomicU64::new(0),
+            halts: AtomicU64::new(0),
+        }
+    }
+}
+
+/// The data of a single core.
+#[repr(C)]
+pub struct PerCpu {
+    /// Points to the block itself, so it can be found through `gs:[0]`. Must stay the first field.
+    self_ptr: AtomicPtr<PerCpu>,
+    core_id: AtomicUsize,
+    lapic_id: AtomicU32,
+    /// The task currently being polled on this core, or 0 if none.
+    current_task: AtomicU64,
+    /// Where the core was last seen executing, or 0 if unknown.
+    last_rip: AtomicU64,
+    /// How many interrupt handlers are running on this core, nested in each other.
+    interrupt_depth: AtomicUsize,
+    run_queue: OnceCell<TaskQueue>,
+    tss: OnceCell<&'static TaskStateSegment>,
+    pub stats: CpuStats,
+}
+
+impl PerCpu {
+    const fn new() -> Self {
+        Self {
+            self_ptr: AtomicPtr::new(ptr::null_mut()),
+            core_id: AtomicUsize::new(0),
+            lapic_id: AtomicU32::new(u32::MAX),
+            current_task: AtomicU64::new(0),
+            last_rip: AtomicU64::new(0),
+            interrupt_depth: AtomicUsize::new(0),
+            run_queue: OnceCell::uninit(),
+            tss: OnceCell::uninit(),
+            stats: CpuStats::new(),
+        }
+    }
+
+    pub fn core_id(&self) -> usize {
+        self.core_id.load(Ordering::Relaxed)
+    }
+
+    /// The LAPIC id of the core, once its Local APIC has been initialized.
+    pub fn lapic_id(&self) -> Option<u32> {
+        Some(self.lapic_id.load(Ordering::Acquire)).filter(|id| *id != u32::MAX)
+    }
+
+    pub fn set_lapic_id(&self, lapic_id: u32) {
+        self.lapic_id.store(lapic_id, Ordering::Release);
+    }
+
+    /// The run queue of the executor on this core, once it has been created.
+    pub fn run_queue(&self) -> Option<&TaskQueue> {
+        self.run_queue.get()
+    }
+
+    pub(crate) fn set_run_queue(&self, queue: TaskQueue) {
+        self.run_queue.init_once(|| queue);
+    }
+
+    /// The id of the task being polled on this core, if any.
+    pub fn current_task(&self) -> Option<TaskId> {
+        Some(self.current_task.load(Ordering::Relaxed)).filter(|id| *id != 0).map(TaskId)
+    }
+
+    pub(crate) fn set_current_task(&self, task: Option<TaskId>) {
+        self.current_task.store(task.map(|id| id.0).unwrap_or(0), Ordering::Relaxed);
+    }
+
+    /// Where the core was last seen executing, as recorded by its interrupt handlers.
+    /// Shows what a core was up to, e.g. when another core panics.
+    pub fn last_rip(&self) -> Option<u64> {
+        Some(self.last_rip.load(Ordering::Relaxed)).filter(|rip| *rip != 0)
+    }
+
+    pub fn record_rip(&self, rip: u64) {
+        self.last_rip.store(rip, Ordering::Relaxed);
+    }
+
+    /// Must be called when an interrupt handler starts running, and paired with [`Self::leave_interrupt`].
+    /// Exceptions aren't counted, only interrupts.
+    pub fn enter_interrupt(&self) {
+        self.interrupt_depth.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn leave_interrupt(&self) {
+        self.interrupt_depth.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    /// Whether the core is running an interrupt handler. Code that faults then can't be blamed
+    /// on the task that got interrupted.
+    pub fn in_interrupt(&self) -> bool {
+        self.interrupt_depth.load(Ordering::Relaxed) != 0
+    }
+
+    /// The TSS of the core, once the kernel loaded it.
+    pub fn tss(&self) -> Option<&'static TaskStateSegment> {
+        self.tss.get().copied()
+    }
+
+    pub fn set_tss(&self, tss: &'static TaskStateSegment) {
+        self.tss.init_once(|| tss);
+    }
+}
+
+const PER_CPU_INIT: PerCpu = PerCpu::new();
+// Statically allocated, so it can be set up before the heap exists
+static PER_CPU: [PerCpu; MAX_CPUS] = [PER_CPU_INIT; MAX_CPUS];
+/// Set once the bootstrap core set up its block. Application processors set up theirs
+/// before running anything else, so from then on every core can use [`this_cpu`].
+static READY: AtomicBool = AtomicBool::new(false);
+
+/// Sets up the per-CPU block of the current core, and points `GS` base to it.
+/// Must be called exactly once on every core, before anything else runs on it.
+pub fn init(core_id: usize) {
+    assert!(core_id < MAX_CPUS, "Core id {} exceeds MAX_CPUS!", core_id);
+    let cpu = &PER_CPU[core_id];
+    cpu.core_id.store(core_id, Ordering::Relaxed);
+    cpu.self_ptr.store(cpu as *const PerCpu as *mut PerCpu, Ordering::Release);
+    GsBase::write(VirtAddr::from_ptr(cpu as *const PerCpu));
+    READY.store(true, Ordering::Release);
+}
+
+/// Returns the per-CPU block of the current core.
+/// Must only be called after [`init`] ran on the current core.
+#[inline]
+pub fn this_cpu() -> &'static PerCpu {
+    let cpu: *const PerCpu;
+    unsafe {
+        asm!("mov {}, gs:[0]", out(reg) cpu, options(nostack, preserves_flags, readonly));
+        &*cpu
+    }
+}
+
+/// Returns the per-CPU block of the current core, or None early during boot, before it is set up.
+#[inline]
+pub fn try_this_cpu() -> Option<&'static PerCpu> {
+    READY.load(Ordering::Acquire).then(this_cpu)
+}
+
+/// Returns the per-CPU block of the given core, if that core has been started.
+pub fn cpu(core_id: usize) -> Option<&'static PerCpu> {
+    PER_CPU.get(core_id).filter(|cpu| !cpu.self_ptr.load(Ordering::Acquire).is_null())
+}
+
+/// Returns the per-CPU blocks of all started cores.
+pub fn cpus() -> impl Iterator<Item = &'static PerCpu> {
+    (0..MAX_CPUS).filter_map(cpu)
+}
@@ -0,0 +1,53 @@
+//! Hooks into the synchronous kernel, for the things that need direct access to the hardware.
+//! The kernel registers its implementation once during boot with [`init`].
+
+use core::fmt;
+use core::ops::Range;
+use conquer_once::spin::OnceCell;
+
+use crate::irq::{IrqSource, IrqError, IrqTrigger};
+
+pub trait Platform: Send + Sync {
+    /// Wakes up the given core if it is halted, by sending it an IPI.
+    fn wake_core(&self, core_id: usize);
+
+    /// Routes a hardware interrupt to `vector` on the given core, and unmasks it.
+    /// Returns how the interrupt is triggered.
+    fn route_irq(&self, source: IrqSource, vector: u8, core_id: usize) -> Result<IrqTrigger, IrqError>;
+    /// Returns the source an interrupt is actually delivered from, like the GSI of an ISA IRQ.
+    /// Masking the resolved source must not need any lookups that take a lock.
+    fn resolve_irq(&self, source: IrqSource) -> IrqSource;
+    /// Masks a hardware interrupt. Must be safe to call from an interrupt handler,
+    /// with a source returned by [`Platform::resolve_irq`].
+    fn mask_irq(&self, source: IrqSource);
+    /// Unmasks a previously routed hardware interrupt.
+    fn unmask_irq(&self, source: IrqSource);
+
+    /// Allocates and maps a stack of at least `size` bytes, with an unmapped guard page directly below it.
+    /// Returns the address range of the stack, or None if out of memory.
+    fn allocate_stack(&self, size: usize) -> Option<Range<usize>>;
+    /// Unmaps a stack returned by [`Platform::allocate_stack`].
+    ///
+    /// This function is unsafe because the caller must guarantee that nothing is using the stack anymore.
+    unsafe fn free_stack(&self, memory: Range<usize>);
+
+    /// Writes straight to the serial port, without taking any locks.
+    /// Safe to call from any interrupt handler, even while the logger is locked.
+    fn emergency_write(&self, args: fmt::Arguments);
+}
+
+static PLATFORM: OnceCell<&'static dyn Platform> = OnceCell::uninit();
+
+pub fn init(platform: &'static dyn Platform) {
+    PLATFORM.init_once(|| platform);
+}
+
+/// Returns the platform hooks. Panics if the kernel did not register them yet.
+pub fn platform() -> &'static dyn Platform {
+    *PLATFORM.get().expect("Platform hooks not initialized!")
+}
+
+/// Returns the id of the core this is called on, or 0 early during boot, before the per-CPU data is set up.
+pub fn current_core() -> usize {
+    crate::percpu::try_this_cpu().map(|cpu| cpu.core_id()).unwrap_or(0)
+}
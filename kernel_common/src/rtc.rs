@@ -1,35 +1,228 @@
-use conquer_once::spin::OnceCell;
+//! Driver for the CMOS real time clock.
+//! The RTC only has a resolution of one second, so it should only be used to find out
+//! the current date and time. See [`crate::wall_clock`] for reading the time.
+
+use x86_64::instructions::port::Port;
+use x86_64::instructions::interrupts::without_interrupts;
 use crate::Mutex;
-use cmos_rtc::{ReadRTC, Time};
 
-static RTC: OnceCell<Mutex<ReadRTC>> = OnceCell::uninit();
+const CMOS_ADDRESS: u16 = 0x70;
+const CMOS_DATA: u16 = 0x71;
+
+const REG_SECONDS: u8 = 0x00;
+const REG_MINUTES: u8 = 0x02;
+const REG_HOURS: u8 = 0x04;
+const REG_DAY: u8 = 0x07;
+const REG_MONTH: u8 = 0x08;
+const REG_YEAR: u8 = 0x09;
+const REG_STATUS_A: u8 = 0x0A;
+const REG_STATUS_B: u8 = 0x0B;
+
+/// Status A: an update is in progress, so the time registers should not be read.
+const STATUS_A_UPDATE_IN_PROGRESS: u8 = 1 << 7;
+/// Status B: stops updates, so the time can be written.
+const STATUS_B_SET: u8 = 1 << 7;
+/// Status B: values are binary instead of BCD.
+const STATUS_B_BINARY: u8 = 1 << 2;
+/// Status B: hours are in 24 hour format.
+const STATUS_B_24_HOUR: u8 = 1 << 1;
+/// In 12 hour format, the top bit of the hours is set for PM.
+const HOUR_PM: u8 = 1 << 7;
 
-fn get_cmos() -> &'static Mutex<ReadRTC> {
-    RTC.get_or_init(|| Mutex::new(ReadRTC::new(0x00, 0x00)))
+/// Used when the firmware does not tell us where the century is stored.
+const DEFAULT_CENTURY: u16 = 20;
+
+/// A date and time as stored in the RTC. The RTC has no notion of time zones,
+/// so we assume it runs in UTC.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RtcTime {
+    pub second: u8,
+    pub minute: u8,
+    pub hour: u8,
+    pub day: u8,
+    pub month: u8,
+    pub year: u16,
 }
 
-pub fn rtc_time() -> Time {
-    get_cmos().lock().read()
+struct Cmos {
+    address: Port<u8>,
+    data: Port<u8>,
+    /// The CMOS register holding the century, from the ACPI FADT. 0 if there is none.
+    century_register: u8,
 }
 
-pub fn rtc_time_seconds() -> u32 {
-    fn time_to_sec(time: Time) -> u32 {
-        time.second as u32 + (time.minute as u32) * 60 + (time.hour as u32) * 60 * 60 + (time.day as u32) * 60 * 60 * 24
+impl Cmos {
+    fn read(&mut self, reg: u8) -> u8 {
+        unsafe {
+            self.address.write(reg);
+            self.data.read()
+        }
+    }
+
+    fn write(&mut self, reg: u8, value: u8) {
+        unsafe {
+            self.address.write(reg);
+            self.data.write(value);
+        }
+    }
+
+    fn read_raw(&mut self) -> [u8; 7] {
+        while self.read(REG_STATUS_A) & STATUS_A_UPDATE_IN_PROGRESS != 0 {
+            core::hint::spin_loop();
+        }
+        let century = if self.century_register != 0 { self.read(self.century_register) } else { 0 };
+        [
+            self.read(REG_SECONDS),
+            self.read(REG_MINUTES),
+            self.read(REG_HOURS),
+            self.read(REG_DAY),
+            self.read(REG_MONTH),
+            self.read(REG_YEAR),
+            century,
+        ]
+    }
+
+    fn read_time(&mut self) -> RtcTime {
+        // An update could still start while reading, so read until we get the same values twice
+        let mut raw = self.read_raw();
+        loop {
+            let again = self.read_raw();
+            if again == raw {
+                break;
+            }
+            raw = again;
+        }
+
+        let status_b = self.read(REG_STATUS_B);
+        let binary = status_b & STATUS_B_BINARY != 0;
+        let decode = |value: u8| if binary { value } else { from_bcd(value) };
+
+        let [second, minute, hour, day, month, year, century] = raw;
+        let pm = hour & HOUR_PM != 0;
+        let mut hour = decode(hour & !HOUR_PM);
+        if status_b & STATUS_B_24_HOUR == 0 {
+            // 12 AM is midnight, 12 PM is noon
+            hour %= 12;
+            if pm {
+                hour += 12;
+            }
+        }
+
+        let century = if self.century_register != 0 { decode(century) as u16 } else { DEFAULT_CENTURY };
+        RtcTime {
+            second: decode(second),
+            minute: decode(minute),
+            hour,
+            day: decode(day),
+            month: decode(month),
+            year: century * 100 + decode(year) as u16,
+        }
     }
-    time_to_sec(rtc_time())
-}
-
-/// Very simple sleep function based on the RTC.
-/// It does not read anything more accurate than seconds, so you can only sleep for
-/// whole seconds.
-/// This function can also break when it runs right over the month/year/century barrier.
-/// It does not take into account the current month, year or century.
-pub fn rtc_sleep(seconds: u32) {
-    let start_sec = rtc_time_seconds();
-    'wait: loop {
-        let now_sec = rtc_time_seconds();
-        if now_sec > start_sec + seconds {
-            break 'wait;
+
+    fn write_time(&mut self, time: &RtcTime) {
+        let status_b = self.read(REG_STATUS_B);
+        let binary = status_b & STATUS_B_BINARY != 0;
+        let encode = |value: u8| if binary { value } else { to_bcd(value) };
+
+        let hour = if status_b & STATUS_B_24_HOUR != 0 {
+            encode(time.hour)
+        } else {
+            let hour_12 = match time.hour % 12 { 0 => 12, hour => hour };
+            encode(hour_12) | if time.hour >= 12 { HOUR_PM } else { 0 }
+        };
+
+        // Stop the clock from updating while we write it
+        self.write(REG_STATUS_B, status_b | STATUS_B_SET);
+        self.write(REG_SECONDS, encode(time.second));
+        self.write(REG_MINUTES, encode(time.minute));
+        self.write(REG_HOURS, hour);
+        self.write(REG_DAY, encode(time.day));
+        self.write(REG_MONTH, encode(time.month));
+        self.write(REG_YEAR, encode((time.year % 100) as u8));
+        if self.century_register != 0 {
+            self.write(self.century_register, encode((time.year / 100) as u8));
         }
+        self.write(REG_STATUS_B, status_b & !STATUS_B_SET);
     }
 }
+
+static CMOS: Mutex<Cmos> = Mutex::new(Cmos {
+    address: Port::new(CMOS_ADDRESS),
+    data: Port::new(CMOS_DATA),
+    century_register: 0,
+});
+
+/// Sets the CMOS register that holds the century, as reported by the ACPI FADT.
+/// Pass 0 if the FADT does not report one, in which case the 21st century is assumed.
+pub fn init(century_register: u8) {
+    without_interrupts(|| CMOS.lock().century_register = century_register);
+}
+
+/// Reads the current date and time from the RTC.
+pub fn rtc_time() -> RtcTime {
+    without_interrupts(|| CMOS.lock().read_time())
+}
+
+/// Writes a new date and time to the RTC.
+pub fn set_rtc_time(time: &RtcTime) {
+    without_interrupts(|| CMOS.lock().write_time(time));
+}
+
+/// Reads the RTC as a UNIX timestamp, in seconds.
+pub fn rtc_time_seconds() -> u64 {
+    datetime_to_unix(&rtc_time())
+}
+
+/// Converts a date and time (in UTC) to seconds since the UNIX epoch.
+pub fn datetime_to_unix(time: &RtcTime) -> u64 {
+    let days = days_from_civil(time.year as i64, time.month as i64, time.day as i64);
+    (days * 86400) as u64 + time.hour as u64 * 3600 + time.minute as u64 * 60 + time.second as u64
+}
+
+/// Converts seconds since the UNIX epoch to a date and time (in UTC).
+pub fn unix_to_datetime(timestamp: u64) -> RtcTime {
+    let days = (timestamp / 86400) as i64;
+    let secs = timestamp % 86400;
+    let (year, month, day) = civil_from_days(days);
+    RtcTime {
+        second: (secs % 60) as u8,
+        minute: (secs / 60 % 60) as u8,
+        hour: (secs / 3600) as u8,
+        day: day as u8,
+        month: month as u8,
+        year: year as u16,
+    }
+}
+
+// Days since 1970-01-01 in the proleptic Gregorian calendar.
+// See: http://howardhinnant.github.io/date_algorithms.html#days_from_civil
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * (month + if month > 2 { -3 } else { 9 }) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
+
+// The inverse of `days_from_civil`, returns (year, month, day).
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let days = days + 719468;
+    let era = days.div_euclid(146097);
+    let day_of_era = days - era * 146097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+fn from_bcd(value: u8) -> u8 {
+    (value & 0x0F) + (value >> 4) * 10
+}
+
+fn to_bcd(value: u8) -> u8 {
+    ((value / 10) << 4) | (value % 10)
+}
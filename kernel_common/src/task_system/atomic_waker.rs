@@ -0,0 +1,76 @@
+//! A waker slot that can be woken from any core, while a task registers itself concurrently.
+//! This follows the same protocol as `futures::task::AtomicWaker`.
+
+use core::cell::UnsafeCell;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use core::task::Waker;
+
+const WAITING: usize = 0;
+const REGISTERING: usize = 0b01;
+const WAKING: usize = 0b10;
+
+pub struct AtomicWaker {
+    state: AtomicUsize,
+    waker: UnsafeCell<Option<Waker>>,
+}
+
+// The waker is only ever accessed by whoever moved the state away from WAITING
+unsafe impl Send for AtomicWaker {}
+unsafe impl Sync for AtomicWaker {}
+
+impl AtomicWaker {
+    pub const fn new() -> Self {
+        Self {
+            state: AtomicUsize::new(WAITING),
+            waker: UnsafeCell::new(None),
+        }
+    }
+
+    /// Registers the waker to be woken by the next call to [`AtomicWaker::wake`].
+    /// If a wake is happening concurrently, the waker is woken right away instead.
+    pub fn register(&self, waker: &Waker) {
+        match self.state.compare_exchange(WAITING, REGISTERING, Ordering::Acquire, Ordering::Acquire).unwrap_or_else(|x| x) {
+            WAITING => unsafe {
+                match &*self.waker.get() {
+                    Some(old_waker) if old_waker.will_wake(waker) => {},
+                    _ => *self.waker.get() = Some(waker.clone()),
+                }
+
+                if let Err(actual) = self.state.compare_exchange(REGISTERING, WAITING, Ordering::AcqRel, Ordering::Acquire) {
+                    // A wake happened while we were registering, so it could not take the waker
+                    debug_assert_eq!(actual, REGISTERING | WAKING);
+                    let waker = (*self.waker.get()).take().unwrap();
+                    self.state.swap(WAITING, Ordering::AcqRel);
+                    waker.wake();
+                }
+            },
+            WAKING => {
+                // Currently being woken, so just wake the new waker right away
+                waker.wake_by_ref();
+            },
+            state => {
+                // Registering concurrently from another thread is a logic error, but harmless
+                debug_assert!(state == REGISTERING || state == REGISTERING | WAKING);
+            },
+        }
+    }
+
+    /// Wakes the registered waker, if there is one.
+    pub fn wake(&self) {
+        if let Some(waker) = self.take() {
+            waker.wake();
+        }
+    }
+
+    /// Takes the registered waker out, if there is one.
+    pub fn take(&self) -> Option<Waker> {
+        match self.state.fetch_or(WAKING, Ordering::AcqRel) {
+            WAITING => {
+                let waker = unsafe { (*self.waker.get()).take() };
+                self.state.fetch_and(!WAKING, Ordering::Release);
+                waker
+            },
+            _ => None,
+        }
+    }
+}
@@ -0,0 +1,110 @@
+//! Builder for spawning tasks with extra options.
+//! ```ignore
+//! TaskBuilder::new()
+//!     .name("lapic_work")
+//!     .priority(Priority::Driver)
+//!     .pinned(true)
+//!     .spawn_on(&spawner, lapic_work());
+//!
+//! TaskBuilder::new()
+//!     .name("fat32")
+//!     .stack_size(128 * 1024)
+//!     .spawn_thread(|ctx| fat32_driver(ctx))?;
+//! ```
+
+use core::future::Future;
+use alloc::string::String;
+
+use super::spawner::Spawner;
+use super::scheduler::with_scheduler;
+use super::join::JoinHandle;
+use super::task::Priority;
+use super::thread::{KernelThread, ThreadContext, ThreadError, DEFAULT_STACK_SIZE};
+
+#[derive(Debug, Clone, Default)]
+pub struct TaskBuilder {
+    name: Option<String>,
+    pinned: bool,
+    priority: Option<Priority>,
+    stack_size: Option<usize>,
+}
+
+impl TaskBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Names the task, which shows up in the task listing.
+    pub fn name(mut self, name: impl Into<String>) -> Self {
+        self.name = Some(name.into());
+        self
+    }
+
+    pub fn task_name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+
+    /// Sets the priority class of the task.
+    /// If not set, the task inherits the priority of the task that spawns it.
+    pub fn priority(mut self, priority: Priority) -> Self {
+        self.priority = Some(priority);
+        self
+    }
+
+    pub fn task_priority(&self) -> Option<Priority> {
+        self.priority
+    }
+
+    /// Pinned tasks stay on the core they are spawned on, and are never stolen by other executors.
+    /// Use this for work that has to happen on a specific core.
+    pub fn pinned(mut self, pinned: bool) -> Self {
+        self.pinned = pinned;
+        self
+    }
+
+    pub fn is_pinned(&self) -> bool {
+        self.pinned
+    }
+
+    /// Sets the stack size, for tasks spawned as kernel threads.
+    pub fn stack_size(mut self, size: usize) -> Self {
+        self.stack_size = Some(size);
+        self
+    }
+
+    pub fn thread_stack_size(&self) -> usize {
+        self.stack_size.unwrap_or(DEFAULT_STACK_SIZE)
+    }
+
+    /// Spawns the task on the executor of a specific spawner.
+    pub fn spawn_on<T: Send + 'static>(self, spawner: &Spawner, future: impl Future<Output = T> + Send + 'static) -> JoinHandle<T> {
+        spawner.spawn_with(self, future)
+    }
+
+    /// Spawns the task on the global scheduler, which picks the least busy executor.
+    pub fn spawn<T: Send + 'static>(self, future: impl Future<Output = T> + Send + 'static) -> JoinHandle<T> {
+        with_scheduler(|sched| sched.spawn_task_with(self, future))
+    }
+
+    /// Spawns a kernel thread on the executor of a specific spawner.
+    /// See [`super::thread`] for how threads work. Fails if the stack can't be allocated.
+    pub fn spawn_thread_on<T, F>(self, spawner: &Spawner, f: F) -> Result<JoinHandle<Result<T, ThreadError>>, ThreadError>
+    where
+        T: Send + 'static,
+        F: FnOnce(&ThreadContext) -> T + Send + 'static,
+    {
+        let thread = KernelThread::new(self.thread_stack_size(), f)?;
+        Ok(self.spawn_on(spawner, thread))
+    }
+
+    /// Spawns a kernel thread on the global scheduler, which picks the least busy executor.
+    /// See [`super::thread`] for how threads work. Fails if the stack can't be allocated.
+    pub fn spawn_thread<T, F>(self, f: F) -> Result<JoinHandle<Result<T, ThreadError>>, ThreadError>
+    where
+        T: Send + 'static,
+        F: FnOnce(&ThreadContext) -> T + Send + 'static,
+    {
+        let thread = KernelThread::new(self.thread_stack_size(), f)?;
+        Ok(self.spawn(thread))
+    }
+}
@@ -1,39 +1,216 @@
-//! A basic delay future, to yield for x amount of time.
-//! Currently relies on the RTC timer, which can only
-//! measure in full seconds.
-// TODO: Once we have a better timer, upgrade this
+//! Timers for async tasks.
+//! Sleeping tasks are kept in a queue of deadlines, ordered by which expires first.
+//! The executors check the queue whenever they wake up, which the periodic timer
+//! interrupt guarantees happens every tick, and only then wake the tasks whose
+//! deadline has passed. A sleeping task therefore never gets polled needlessly.
 
 use core::{
-    future::Future,
-    pin::Pin,
+    cmp::Ordering as CmpOrdering,
+    future::{Future, poll_fn},
+    pin::{Pin, pin},
+    sync::atomic::{AtomicU64, Ordering},
     task::{
         Context,
         Poll,
-    }
+    },
+    time::Duration,
 };
+use alloc::collections::BinaryHeap;
+use alloc::sync::Arc;
+
+use super::atomic_waker::AtomicWaker;
+use crate::time::Instant;
+
+struct TimerEntry {
+    deadline: Instant,
+    /// Breaks ties between equal deadlines, so they expire in the order they were added.
+    seq: u64,
+    waker: Arc<AtomicWaker>,
+}
+
+impl PartialEq for TimerEntry {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == CmpOrdering::Equal
+    }
+}
+
+impl Eq for TimerEntry {}
+
+impl PartialOrd for TimerEntry {
+    fn partial_cmp(&self, other: &Self) -> Option<CmpOrdering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for TimerEntry {
+    // Reversed, as BinaryHeap is a max-heap and we want the earliest deadline on top
+    fn cmp(&self, other: &Self) -> CmpOrdering {
+        other.deadline.cmp(&self.deadline).then_with(|| other.seq.cmp(&self.seq))
+    }
+}
+
+impl TimerEntry {
+    /// The [`Sleep`] holds the other reference, so this is true once it got dropped.
+    fn is_cancelled(&self) -> bool {
+        Arc::strong_count(&self.waker) == 1
+    }
+}
+
+struct TimerQueue {
+    entries: BinaryHeap<TimerEntry>,
+    next_seq: u64,
+    /// How many entries belong to a dropped [`Sleep`]. Those are only removed in bulk,
+    /// as a heap can't remove arbitrary entries cheaply.
+    cancelled: usize,
+}
+
+impl TimerQueue {
+    fn update_next_deadline(&self) {
+        let next = self.entries.peek().map(|entry| entry.deadline.as_nanos()).unwrap_or(u64::MAX);
+        NEXT_DEADLINE.store(next, Ordering::Release);
+    }
+}
+
+static TIMER_QUEUE: spin::Mutex<TimerQueue> = spin::Mutex::new(TimerQueue {
+    entries: BinaryHeap::new(),
+    next_seq: 0,
+    cancelled: 0,
+});
+
+/// The earliest deadline in the queue in nanoseconds, so the executors can skip the lock
+/// when nothing expired yet. Only written with the queue locked.
+static NEXT_DEADLINE: AtomicU64 = AtomicU64::new(u64::MAX);
+
+fn add_timer(deadline: Instant, waker: Arc<AtomicWaker>) {
+    let mut queue = TIMER_QUEUE.lock();
+    let seq = queue.next_seq;
+    queue.next_seq += 1;
+    queue.entries.push(TimerEntry { deadline, seq, waker });
+    NEXT_DEADLINE.fetch_min(deadline.as_nanos(), Ordering::AcqRel);
+}
+
+/// Wakes every task whose deadline has passed. Called by the executors.
+pub fn process_expired() {
+    let now = Instant::now();
+    if now.as_nanos() < NEXT_DEADLINE.load(Ordering::Acquire) {
+        return;
+    }
+    // Another core is already processing the queue
+    let Some(mut queue) = TIMER_QUEUE.try_lock() else { return; };
 
-use crate::rtc::rtc_time_seconds;
+    let mut expired = alloc::vec::Vec::new();
+    while queue.entries.peek().map(|entry| entry.deadline <= now).unwrap_or(false) {
+        let entry = queue.entries.pop().unwrap();
+        if entry.is_cancelled() {
+            queue.cancelled -= 1;
+        } else {
+            expired.push(entry);
+        }
+    }
+    queue.update_next_deadline();
+    drop(queue);
+
+    // Woken without the lock held, as the woken task might be polled on another core right away
+    for entry in expired {
+        entry.waker.wake();
+    }
+}
+
+/// A future that completes once its deadline has passed. Created by [`sleep`] and [`sleep_until`].
+pub struct Sleep {
+    deadline: Instant,
+    waker: Option<Arc<AtomicWaker>>,
+}
 
-struct DelayFuture {
-    finish: u32, // In seconds
+impl Sleep {
+    pub fn deadline(&self) -> Instant {
+        self.deadline
+    }
 }
 
-impl Future for DelayFuture {
+impl Future for Sleep {
     type Output = ();
     fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
-        if rtc_time_seconds() > self.finish {
+        if Instant::now() >= self.deadline {
+            return Poll::Ready(());
+        }
+
+        match &self.waker {
+            Some(waker) => waker.register(cx.waker()),
+            None => {
+                // Only added to the queue once. Later polls just update the waker
+                let waker = Arc::new(AtomicWaker::new());
+                waker.register(cx.waker());
+                add_timer(self.deadline, waker.clone());
+                self.waker = Some(waker);
+            },
+        }
+
+        if Instant::now() >= self.deadline {
             Poll::Ready(())
         } else {
-            cx.waker().wake_by_ref();
             Poll::Pending
         }
     }
 }
 
+impl Drop for Sleep {
+    fn drop(&mut self) {
+        let Some(waker) = self.waker.take() else { return; };
+        // The reference is dropped with the lock held, so the queue always agrees
+        // with `cancelled` about which entries are cancelled
+        let mut queue = TIMER_QUEUE.lock();
+        let queued = Arc::strong_count(&waker) > 1;
+        drop(waker);
+        if !queued {
+            // Already expired and removed
+            return;
+        }
+        queue.cancelled += 1;
+        // Compacting once over half the queue is cancelled keeps it from growing without
+        // bound, e.g. with `timeout` in a loop, while staying cheap on average
+        if queue.cancelled * 2 > queue.entries.len() {
+            queue.entries.retain(|entry| !entry.is_cancelled());
+            queue.cancelled = 0;
+            queue.update_next_deadline();
+        }
+    }
+}
+
+/// Sleeps for at least `duration`. The resolution is one timer tick.
+pub fn sleep(duration: Duration) -> Sleep {
+    sleep_until(Instant::now() + duration)
+}
+
+/// Sleeps until `deadline` has passed.
+pub fn sleep_until(deadline: Instant) -> Sleep {
+    Sleep {
+        deadline,
+        waker: None,
+    }
+}
+
+/// Returned by [`timeout`] when the future did not complete in time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Elapsed;
+
+/// Runs `future`, but gives up once `duration` has passed. The future is dropped in that case.
+pub async fn timeout<F: Future>(future: F, duration: Duration) -> Result<F::Output, Elapsed> {
+    let mut future = pin!(future);
+    let mut sleep = sleep(duration);
+    poll_fn(|cx| {
+        if let Poll::Ready(output) = future.as_mut().poll(cx) {
+            return Poll::Ready(Ok(output));
+        }
+        match Pin::new(&mut sleep).poll(cx) {
+            Poll::Ready(()) => Poll::Ready(Err(Elapsed)),
+            Poll::Pending => Poll::Pending,
+        }
+    }).await
+}
+
+/// Sleeps for the given amount of seconds.
 #[inline]
 pub async fn delay(sec: u32) {
-    let start = rtc_time_seconds();
-    DelayFuture {
-        finish: start + sec,
-    }.await
+    sleep(Duration::from_secs(sec as u64)).await
 }
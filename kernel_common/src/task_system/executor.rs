@@ -1,21 +1,145 @@
-use alloc::sync::Arc;
+use alloc::sync::{Arc, Weak};
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use core::task::{Context, Poll};
 
 use crossbeam_queue::SegQueue;
 use futures_task::waker_ref;
+use x86_64::instructions::interrupts;
 
-use super::task::ArcTask;
+use super::task::{Task, ArcTask, Priority};
 use super::spawner::Spawner;
+use super::watchdog;
+use crate::time::Instant;
+use crate::percpu::this_cpu;
 
-pub type TaskQueue = Arc<SegQueue<ArcTask>>;
+pub type TaskQueue = Arc<RunQueue>;
+
+/// The run queues of every executor, so idle executors can steal work from busy ones.
+static RUN_QUEUES: spin::Mutex<Vec<TaskQueue>> = spin::Mutex::new(Vec::new());
+
+/// Every this many pops, a different priority class gets to go first,
+/// so a flood of high priority tasks can't starve the lower classes completely.
+const STARVATION_INTERVAL: usize = 8;
+
+const QUEUE_INIT: SegQueue<ArcTask> = SegQueue::new();
+
+/// The queues of tasks that are ready to be polled by the executor of a single core, one per priority class.
+pub struct RunQueue {
+    queues: [SegQueue<ArcTask>; Priority::COUNT],
+    /// Pinned tasks are kept apart, so stealing never has to look at them.
+    pinned: [SegQueue<ArcTask>; Priority::COUNT],
+    core_id: usize,
+    /// Set while the executor is (about to be) halted.
+    idle: AtomicBool,
+    pops: AtomicUsize,
+    /// The task the executor is polling right now.
+    current: spin::Mutex<Option<Weak<Task>>>,
+}
+
+impl RunQueue {
+    pub fn new(core_id: usize) -> Self {
+        Self {
+            queues: [QUEUE_INIT; Priority::COUNT],
+            pinned: [QUEUE_INIT; Priority::COUNT],
+            core_id,
+            idle: AtomicBool::new(false),
+            pops: AtomicUsize::new(0),
+            current: spin::Mutex::new(None),
+        }
+    }
+
+    /// The core whose executor runs the tasks in this queue.
+    pub fn core_id(&self) -> usize {
+        self.core_id
+    }
+
+    /// Pushes a task onto the queue. If the owning core is halted, it gets woken up with an IPI.
+    pub fn push(&self, task: ArcTask) {
+        let class = task.priority() as usize;
+        if task.is_pinned() {
+            self.pinned[class].push(task);
+        } else {
+            self.queues[class].push(task);
+        }
+        // A halted core on the current core can only be us, and we're clearly not halted
+        if self.idle.load(Ordering::SeqCst) && self.core_id != crate::platform::current_core() {
+            crate::platform::platform().wake_core(self.core_id);
+        }
+    }
+
+    /// Pops the next task, from the highest priority class that has one.
+    /// Every [`STARVATION_INTERVAL`] pops the search starts at the next class instead, round robin.
+    pub fn pop(&self) -> Option<ArcTask> {
+        let pops = self.pops.fetch_add(1, Ordering::Relaxed);
+        let first = if pops % STARVATION_INTERVAL == STARVATION_INTERVAL - 1 {
+            (pops / STARVATION_INTERVAL) % Priority::COUNT
+        } else {
+            0
+        };
+        // Within a class, pinned and other tasks take turns, so neither can starve the other
+        let pinned_first = pops % 2 == 0;
+        (0..Priority::COUNT)
+            .map(|i| (first + i) % Priority::COUNT)
+            .find_map(|class| {
+                let (preferred, other) = if pinned_first {
+                    (&self.pinned[class], &self.queues[class])
+                } else {
+                    (&self.queues[class], &self.pinned[class])
+                };
+                preferred.pop().or_else(|| other.pop())
+            })
+    }
+
+    /// Takes a task for another executor, from the lowest priority class that has one.
+    /// The owner keeps its most urgent work, and its starvation rotation is left alone.
+    /// Pinned tasks are never returned.
+    pub fn steal(&self) -> Option<ArcTask> {
+        self.queues.iter().rev().find_map(|queue| queue.pop())
+    }
+
+    /// The amount of tasks [`RunQueue::steal`] could take.
+    pub fn stealable_len(&self) -> usize {
+        self.queues.iter().map(|queue| queue.len()).sum()
+    }
+
+    pub fn len(&self) -> usize {
+        self.stealable_len() + self.pinned.iter().map(|queue| queue.len()).sum::<usize>()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.queues.iter().chain(self.pinned.iter()).all(|queue| queue.is_empty())
+    }
+
+    pub fn is_idle(&self) -> bool {
+        self.idle.load(Ordering::SeqCst)
+    }
+}
+
+/// Returns the task that is being polled on the current core, if any.
+pub fn current_task() -> Option<ArcTask> {
+    let queue = crate::percpu::try_this_cpu()?.run_queue()?;
+    let current = queue.current.lock().as_ref().and_then(|task| task.upgrade());
+    current
+}
+
+/// Wakes the tasks waiting on interrupts and timers.
+fn process_events() {
+    crate::irq::process_pending();
+    super::delay::process_expired();
+}
 
 pub struct SimpleExecutor {
     task_queue: TaskQueue,
 }
 
 impl SimpleExecutor {
+    /// Creates the executor for the current core. It must only be run on this core.
     pub fn new() -> SimpleExecutor {
-        let task_queue = Arc::new(SegQueue::new());
+        let cpu = this_cpu();
+        let task_queue = Arc::new(RunQueue::new(cpu.core_id()));
+        cpu.set_run_queue(task_queue.clone());
+        RUN_QUEUES.lock().push(task_queue.clone());
         SimpleExecutor {
             task_queue,
         }
@@ -47,16 +171,33 @@ impl SimpleExecutor {
     }
 
     fn run_internal(&self) -> ! {
+        let cpu = this_cpu();
         loop {
+            process_events();
             // TODO: This polls tasks until they are ready
             //       For a better implementation, see:
             //       https://os.phil-opp.com/async-await/#executor-with-waker-support
             while let Some(task) = self.task_queue.pop() {
                 let mut future_slot = task.future.lock();
+                if task.is_aborted() {
+                    // Dropped here, so it always happens on the owning core
+                    drop(future_slot.take());
+                    continue;
+                }
                 if let Some(mut future) = future_slot.take() {
                     let waker = waker_ref(&task);
                     let mut context = Context::from_waker(&*waker);
-                    match future.as_mut().poll(&mut context) {
+                    *self.task_queue.current.lock() = Some(Arc::downgrade(&task));
+                    cpu.set_current_task(Some(task.id()));
+                    cpu.stats.polls.fetch_add(1, Ordering::Relaxed);
+                    let start = Instant::now();
+                    watchdog::poll_started(self.task_queue.core_id, task.id());
+                    let poll = future.as_mut().poll(&mut context);
+                    watchdog::poll_finished(self.task_queue.core_id, &task);
+                    task.record_poll(start.elapsed());
+                    *self.task_queue.current.lock() = None;
+                    cpu.set_current_task(None);
+                    match poll {
                         Poll::Ready(()) => {} // Task done
                         Poll::Pending => {
                             *future_slot = Some(future);
@@ -64,7 +205,52 @@ impl SimpleExecutor {
                         },
                     }
                 }
+                process_events();
+            }
+
+            if !self.steal_tasks() {
+                self.halt_until_woken();
             }
         }
     }
+
+    /// Moves half of the stealable tasks of the busiest other executor over to this one.
+    /// Executors that are halted will get to their tasks soon enough, so they're left alone.
+    /// Pinned tasks are never stolen. Returns whether any task was stolen.
+    fn steal_tasks(&self) -> bool {
+        // Another executor is already looking for work, so the queues will look different soon anyway
+        let Some(queues) = RUN_QUEUES.try_lock() else { return false; };
+        let victim = queues.iter()
+            .filter(|queue| !Arc::ptr_eq(queue, &self.task_queue) && !queue.is_idle())
+            .max_by_key(|queue| queue.stealable_len())
+            .filter(|queue| queue.stealable_len() > 0)
+            .cloned();
+        drop(queues);
+        let Some(victim) = victim else { return false; };
+
+        let mut stolen = 0;
+        for _ in 0..(victim.stealable_len() + 1) / 2 {
+            let Some(task) = victim.steal() else { break; };
+            task.set_queue(self.task_queue.clone());
+            self.task_queue.push(task);
+            stolen += 1;
+        }
+        stolen > 0
+    }
+
+    /// Halts the core until the next interrupt, unless work arrived in the meantime.
+    /// Interrupts are disabled for the final check, so a wakeup IPI can't slip in between
+    /// the check and the `hlt`. `sti` only takes effect after the next instruction,
+    /// so the IPI will then be delivered while halted, and wakes us up.
+    fn halt_until_woken(&self) {
+        self.task_queue.idle.store(true, Ordering::SeqCst);
+        interrupts::disable();
+        if self.task_queue.is_empty() && !crate::irq::has_pending() {
+            this_cpu().stats.halts.fetch_add(1, Ordering::Relaxed);
+            interrupts::enable_and_hlt();
+        } else {
+            interrupts::enable();
+        }
+        self.task_queue.idle.store(false, Ordering::SeqCst);
+    }
 }
@@ -0,0 +1,144 @@
+//! Handles to spawned tasks, to wait for their output or abort them.
+
+use core::{
+    future::Future,
+    pin::Pin,
+    task::{
+        Context,
+        Poll,
+    },
+};
+use alloc::sync::{Arc, Weak};
+
+use super::atomic_waker::AtomicWaker;
+use super::task::{Task, ArcTask};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JoinError {
+    /// The task was aborted before it finished.
+    Aborted,
+}
+
+enum JoinSlot<T> {
+    Running,
+    Finished(T),
+    Aborted,
+    /// The output was already handed to the JoinHandle.
+    Taken,
+}
+
+pub(crate) struct JoinState<T> {
+    slot: spin::Mutex<JoinSlot<T>>,
+    waker: AtomicWaker,
+}
+
+impl<T> JoinState<T> {
+    fn complete(&self, slot: JoinSlot<T>) {
+        *self.slot.lock() = slot;
+        self.waker.wake();
+    }
+}
+
+/// Wraps the future of a spawned task, to store its output for the JoinHandle.
+pub(crate) struct JoinFuture<F: Future> {
+    future: F,
+    state: Arc<JoinState<F::Output>>,
+    finished: bool,
+}
+
+impl<F: Future> Future for JoinFuture<F> {
+    type Output = ();
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        // Safety: `future` is never moved out of, and the other fields are not structurally pinned
+        let this = unsafe { self.get_unchecked_mut() };
+        let future = unsafe { Pin::new_unchecked(&mut this.future) };
+        match future.poll(cx) {
+            Poll::Ready(output) => {
+                this.finished = true;
+                this.state.complete(JoinSlot::Finished(output));
+                Poll::Ready(())
+            },
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+impl<F: Future> Drop for JoinFuture<F> {
+    // The executor drops the future without finishing it when the task gets aborted
+    fn drop(&mut self) {
+        if !self.finished {
+            self.state.complete(JoinSlot::Aborted);
+        }
+    }
+}
+
+/// Creates the future that gets spawned as a task, and the state shared with its JoinHandle.
+pub(crate) fn join_pair<F: Future>(future: F) -> (JoinFuture<F>, Arc<JoinState<F::Output>>) {
+    let state = Arc::new(JoinState {
+        slot: spin::Mutex::new(JoinSlot::Running),
+        waker: AtomicWaker::new(),
+    });
+    (JoinFuture { future, state: state.clone(), finished: false }, state)
+}
+
+/// A handle to a spawned task. Awaiting it gives the output of the task.
+/// Dropping the handle detaches the task, which then keeps running in the background.
+pub struct JoinHandle<T> {
+    state: Arc<JoinState<T>>,
+    task: Weak<Task>,
+}
+
+impl<T> JoinHandle<T> {
+    pub(crate) fn new(state: Arc<JoinState<T>>, task: &ArcTask) -> Self {
+        Self {
+            state,
+            task: Arc::downgrade(task),
+        }
+    }
+
+    /// Aborts the task. Its future gets dropped by its owning executor, the next time it would be polled.
+    /// Awaiting the handle afterwards gives [`JoinError::Aborted`], unless the task finished first.
+    pub fn abort(&self) {
+        if let Some(task) = self.task.upgrade() {
+            task.abort();
+        }
+    }
+
+    /// Lets the task keep running in the background, without a way to get its output.
+    pub fn detach(self) {}
+
+    /// Returns whether the task finished or was aborted.
+    pub fn is_finished(&self) -> bool {
+        !matches!(*self.state.slot.lock(), JoinSlot::Running)
+    }
+
+    /// The core whose executor currently owns the task, if it is still running.
+    pub fn core_id(&self) -> Option<usize> {
+        self.task.upgrade().map(|task| task.core_id())
+    }
+}
+
+impl<T> Future for JoinHandle<T> {
+    type Output = Result<T, JoinError>;
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let take = |slot: &mut JoinSlot<T>| match core::mem::replace(slot, JoinSlot::Taken) {
+            JoinSlot::Finished(output) => Some(Ok(output)),
+            JoinSlot::Aborted => Some(Err(JoinError::Aborted)),
+            JoinSlot::Running => {
+                *slot = JoinSlot::Running;
+                None
+            },
+            JoinSlot::Taken => panic!("JoinHandle polled after completion!"),
+        };
+
+        if let Some(result) = take(&mut self.state.slot.lock()) {
+            return Poll::Ready(result);
+        }
+        self.state.waker.register(cx.waker());
+        // The task might have finished while registering
+        match take(&mut self.state.slot.lock()) {
+            Some(result) => Poll::Ready(result),
+            None => Poll::Pending,
+        }
+    }
+}
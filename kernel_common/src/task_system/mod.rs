@@ -2,5 +2,11 @@ pub mod executor;
 pub mod task;
 pub mod spawner;
 pub mod scheduler;
+pub mod builder;
+pub mod join;
+pub mod sync;
+pub mod watchdog;
+pub mod thread;
+pub mod atomic_waker;
 
 pub mod delay;
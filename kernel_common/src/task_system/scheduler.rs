@@ -9,6 +9,8 @@ use core::pin::Pin;
 use sync_wrapper::SyncWrapper;
 
 use super::spawner::Spawner;
+use super::builder::TaskBuilder;
+use super::join::JoinHandle;
 use crate::services::*;
 
 pub struct SchedulerMessage {
@@ -41,7 +43,7 @@ pub fn with_scheduler<T, F: FnOnce(&mut Scheduler) -> T>(f: F) -> T {
     res
 }
 
-pub fn scheduler_spawn_task(task: impl core::future::Future<Output = ()> + Send + 'static) -> usize {
+pub fn scheduler_spawn_task<T: Send + 'static>(task: impl core::future::Future<Output = T> + Send + 'static) -> JoinHandle<T> {
     with_scheduler(|sched| sched.spawn_task(task))
 }
 
@@ -77,7 +79,12 @@ impl Scheduler {
     /// Spawns an async task. Automatically tries to pick the best spawner
     /// to spawn the task on.
     /// Panics if there are no spawners in the scheduler.
-    pub fn spawn_task(&self, task: impl core::future::Future<Output = ()> + Send + 'static) -> usize {
+    pub fn spawn_task<T: Send + 'static>(&self, task: impl core::future::Future<Output = T> + Send + 'static) -> JoinHandle<T> {
+        self.spawn_task_with(TaskBuilder::new(), task)
+    }
+
+    /// Spawns an async task with the options from the builder, on the least busy executor.
+    pub fn spawn_task_with<T: Send + 'static>(&self, builder: TaskBuilder, task: impl core::future::Future<Output = T> + Send + 'static) -> JoinHandle<T> {
         let mut lowest_idx = 0;
         let mut lowest_count = usize::MAX;
         for (i, spawner) in self.spawners.iter().enumerate() {
@@ -87,7 +94,6 @@ impl Scheduler {
                 lowest_count = task_count;
             }
         }
-        self.spawners[lowest_idx].spawn(task);
-        lowest_idx
+        self.spawners[lowest_idx].spawn_with(builder, task)
     }
 }
@@ -1,7 +1,9 @@
 use core::future::Future;
 
-use super::task::{Task, ArcTask};
+use super::task::Task;
 use super::executor::TaskQueue;
+use super::builder::TaskBuilder;
+use super::join::{JoinHandle, join_pair};
 
 #[derive(Clone)]
 pub struct Spawner {
@@ -15,15 +17,22 @@ impl Spawner {
         }
     }
 
-    pub fn spawn(&self, future: impl Future<Output = ()> + Send + 'static) {
-        let task = Task::new(future, self.task_queue.clone());
-        self.task_queue.push(task);
+    pub fn spawn<T: Send + 'static>(&self, future: impl Future<Output = T> + Send + 'static) -> JoinHandle<T> {
+        self.spawn_with(TaskBuilder::new(), future)
     }
 
-    // TODO: Proper async version?
-    pub async fn spawn_async(&self, future: impl Future<Output = ()> + Send + 'static) {
-        let task = Task::new(future, self.task_queue.clone());
+    /// Spawns a task with the options from the builder. See [`TaskBuilder::spawn_on`].
+    pub(crate) fn spawn_with<T: Send + 'static>(&self, builder: TaskBuilder, future: impl Future<Output = T> + Send + 'static) -> JoinHandle<T> {
+        let (future, state) = join_pair(future);
+        let task = Task::from_builder(builder, future, self.task_queue.clone());
+        let handle = JoinHandle::new(state, &task);
         self.task_queue.push(task);
+        handle
+    }
+
+    /// The core whose executor this spawner spawns tasks on.
+    pub fn core_id(&self) -> usize {
+        self.task_queue.core_id()
     }
 
     /// Returns the amount of tasks currently in the TaskQueue.
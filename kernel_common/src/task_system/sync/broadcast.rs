@@ -0,0 +1,195 @@
+//! A bounded multi-producer, multi-consumer channel, where every receiver sees every value.
+//! Senders never wait. When a receiver falls too far behind, the oldest values are
+//! dropped for it, and it gets told how many it missed.
+
+use core::future::poll_fn;
+use core::task::Poll;
+use alloc::collections::VecDeque;
+use alloc::sync::{Arc, Weak};
+use alloc::vec::Vec;
+
+use crate::task_system::atomic_waker::AtomicWaker;
+
+/// Every receiver was dropped. Contains the value that could not be sent.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SendError<T>(pub T);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RecvError {
+    /// Every sender was dropped, and there are no values left.
+    Closed,
+    /// The receiver fell behind, and this many values were skipped.
+    Lagged(u64),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TryRecvError {
+    Empty,
+    Closed,
+    Lagged(u64),
+}
+
+struct State<T> {
+    /// The values that are still buffered, with their sequence numbers.
+    buffer: VecDeque<(u64, T)>,
+    next_seq: u64,
+    senders: usize,
+    receivers: usize,
+    wakers: Vec<Weak<AtomicWaker>>,
+}
+
+struct Shared<T> {
+    state: spin::Mutex<State<T>>,
+    capacity: usize,
+}
+
+/// Creates a channel that buffers up to `capacity` values for its slowest receiver.
+pub fn channel<T: Clone>(capacity: usize) -> (Sender<T>, Receiver<T>) {
+    assert!(capacity > 0, "broadcast channel capacity must be at least 1!");
+    let shared = Arc::new(Shared {
+        state: spin::Mutex::new(State {
+            buffer: VecDeque::with_capacity(capacity),
+            next_seq: 0,
+            senders: 1,
+            receivers: 0,
+            wakers: Vec::new(),
+        }),
+        capacity,
+    });
+    let receiver = Receiver::new(&shared, 0);
+    (Sender { shared }, receiver)
+}
+
+pub struct Sender<T> {
+    shared: Arc<Shared<T>>,
+}
+
+impl<T: Clone> Sender<T> {
+    /// Sends a value to every receiver. Returns the amount of receivers.
+    pub fn send(&self, value: T) -> Result<usize, SendError<T>> {
+        let mut state = self.shared.state.lock();
+        if state.receivers == 0 {
+            return Err(SendError(value));
+        }
+        let seq = state.next_seq;
+        state.next_seq += 1;
+        state.buffer.push_back((seq, value));
+        if state.buffer.len() > self.shared.capacity {
+            state.buffer.pop_front();
+        }
+        let receivers = state.receivers;
+        let wakers = Self::take_wakers(&mut state);
+        drop(state);
+        for waker in wakers {
+            waker.wake();
+        }
+        Ok(receivers)
+    }
+
+    fn take_wakers(state: &mut State<T>) -> Vec<Arc<AtomicWaker>> {
+        state.wakers.retain(|waker| waker.strong_count() > 0);
+        state.wakers.iter().filter_map(|waker| waker.upgrade()).collect()
+    }
+
+    /// Creates a new receiver, which will see every value sent from now on.
+    pub fn subscribe(&self) -> Receiver<T> {
+        let next = self.shared.state.lock().next_seq;
+        Receiver::new(&self.shared, next)
+    }
+
+    pub fn receiver_count(&self) -> usize {
+        self.shared.state.lock().receivers
+    }
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Self {
+        self.shared.state.lock().senders += 1;
+        Self { shared: self.shared.clone() }
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        let mut state = self.shared.state.lock();
+        state.senders -= 1;
+        if state.senders == 0 {
+            // Wake everyone, so they find out the channel is closed
+            let wakers: Vec<Arc<AtomicWaker>> = state.wakers.iter().filter_map(|waker| waker.upgrade()).collect();
+            drop(state);
+            for waker in wakers {
+                waker.wake();
+            }
+        }
+    }
+}
+
+pub struct Receiver<T> {
+    shared: Arc<Shared<T>>,
+    /// Sequence number of the next value this receiver will see.
+    next: u64,
+    waker: Arc<AtomicWaker>,
+}
+
+impl<T> Receiver<T> {
+    fn new(shared: &Arc<Shared<T>>, next: u64) -> Self {
+        let waker = Arc::new(AtomicWaker::new());
+        let mut state = shared.state.lock();
+        state.receivers += 1;
+        state.wakers.push(Arc::downgrade(&waker));
+        drop(state);
+        Self {
+            shared: shared.clone(),
+            next,
+            waker,
+        }
+    }
+}
+
+impl<T: Clone> Receiver<T> {
+    /// Receives the next value.
+    pub async fn recv(&mut self) -> Result<T, RecvError> {
+        poll_fn(|cx| {
+            // Registered up front, as a value sent after the check below would otherwise not wake us
+            self.waker.register(cx.waker());
+            match self.try_recv() {
+                Ok(value) => Poll::Ready(Ok(value)),
+                Err(TryRecvError::Closed) => Poll::Ready(Err(RecvError::Closed)),
+                Err(TryRecvError::Lagged(skipped)) => Poll::Ready(Err(RecvError::Lagged(skipped))),
+                Err(TryRecvError::Empty) => Poll::Pending,
+            }
+        }).await
+    }
+
+    pub fn try_recv(&mut self) -> Result<T, TryRecvError> {
+        let state = self.shared.state.lock();
+        if let Some((oldest, _)) = state.buffer.front() {
+            if self.next < *oldest {
+                let skipped = oldest - self.next;
+                self.next = *oldest;
+                return Err(TryRecvError::Lagged(skipped));
+            }
+        }
+        if self.next < state.next_seq {
+            let (_, value) = state.buffer.iter().find(|(seq, _)| *seq == self.next).unwrap();
+            let value = value.clone();
+            self.next += 1;
+            return Ok(value);
+        }
+        if state.senders == 0 {
+            Err(TryRecvError::Closed)
+        } else {
+            Err(TryRecvError::Empty)
+        }
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        let mut state = self.shared.state.lock();
+        state.receivers -= 1;
+        // Senders prune dead wakers too, but there might never be another send
+        let waker = Arc::as_ptr(&self.waker);
+        state.wakers.retain(|other| other.as_ptr() != waker);
+    }
+}
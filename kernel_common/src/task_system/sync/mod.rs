@@ -0,0 +1,17 @@
+//! Synchronization primitives for async tasks.
+//! Unlike `spin::Mutex`, waiting on these suspends the task instead of spinning the core,
+//! so they can be held across `.await` points. They are woken through the task's waker,
+//! so they work across cores like any other wakeup.
+
+mod semaphore;
+mod mutex;
+mod rwlock;
+mod notify;
+pub mod oneshot;
+pub mod mpsc;
+pub mod broadcast;
+
+pub use semaphore::{Semaphore, SemaphorePermit, Acquire, AcquireError, TryAcquireError};
+pub use mutex::{Mutex, MutexGuard};
+pub use rwlock::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+pub use notify::{Notify, Notified};
@@ -0,0 +1,157 @@
+//! A bounded multi-producer, single-consumer channel.
+//! Senders wait when the channel is full, so a slow receiver slows the senders down.
+
+use core::future::poll_fn;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use core::task::Poll;
+use alloc::collections::VecDeque;
+use alloc::sync::Arc;
+
+use super::semaphore::{Semaphore, TryAcquireError};
+use crate::task_system::atomic_waker::AtomicWaker;
+
+/// The receiver was dropped. Contains the value that could not be sent.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SendError<T>(pub T);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TrySendError<T> {
+    Full(T),
+    Closed(T),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TryRecvError {
+    Empty,
+    /// Every sender was dropped, and the channel is empty.
+    Disconnected,
+}
+
+struct Chan<T> {
+    queue: spin::Mutex<VecDeque<T>>,
+    /// One permit per free slot. Closed when the receiver is dropped.
+    capacity: Semaphore,
+    senders: AtomicUsize,
+    rx_waker: AtomicWaker,
+}
+
+/// Creates a channel that holds at most `capacity` values.
+pub fn channel<T>(capacity: usize) -> (Sender<T>, Receiver<T>) {
+    assert!(capacity > 0, "mpsc channel capacity must be at least 1!");
+    let chan = Arc::new(Chan {
+        queue: spin::Mutex::new(VecDeque::with_capacity(capacity)),
+        capacity: Semaphore::new(capacity),
+        senders: AtomicUsize::new(1),
+        rx_waker: AtomicWaker::new(),
+    });
+    (Sender { chan: chan.clone() }, Receiver { chan })
+}
+
+pub struct Sender<T> {
+    chan: Arc<Chan<T>>,
+}
+
+impl<T> Sender<T> {
+    /// Sends a value, waiting for a free slot if the channel is full.
+    pub async fn send(&self, value: T) -> Result<(), SendError<T>> {
+        match self.chan.capacity.acquire().await {
+            Ok(permit) => permit.forget(),
+            Err(_) => return Err(SendError(value)),
+        }
+        // The receiver might have gone away while we were waiting for the slot
+        self.push(value).map_err(SendError)
+    }
+
+    pub fn try_send(&self, value: T) -> Result<(), TrySendError<T>> {
+        match self.chan.capacity.try_acquire() {
+            Ok(permit) => permit.forget(),
+            Err(TryAcquireError::NoPermits) => return Err(TrySendError::Full(value)),
+            Err(TryAcquireError::Closed) => return Err(TrySendError::Closed(value)),
+        }
+        self.push(value).map_err(TrySendError::Closed)
+    }
+
+    /// Queues a value that got a slot. Gives it back if the channel was closed in the meantime.
+    fn push(&self, value: T) -> Result<(), T> {
+        let mut queue = self.chan.queue.lock();
+        // Closing happens with the queue locked, so this can't race with the receiver going away
+        if self.chan.capacity.is_closed() {
+            return Err(value);
+        }
+        queue.push_back(value);
+        drop(queue);
+        self.chan.rx_waker.wake();
+        Ok(())
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.chan.capacity.is_closed()
+    }
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Self {
+        self.chan.senders.fetch_add(1, Ordering::Relaxed);
+        Self { chan: self.chan.clone() }
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        if self.chan.senders.fetch_sub(1, Ordering::AcqRel) == 1 {
+            // The receiver has to find out there's nothing more coming
+            self.chan.rx_waker.wake();
+        }
+    }
+}
+
+pub struct Receiver<T> {
+    chan: Arc<Chan<T>>,
+}
+
+impl<T> Receiver<T> {
+    /// Receives the next value. Returns None once every sender is gone and the channel is empty.
+    pub async fn recv(&mut self) -> Option<T> {
+        poll_fn(|cx| {
+            match self.try_recv() {
+                Ok(value) => return Poll::Ready(Some(value)),
+                Err(TryRecvError::Disconnected) => return Poll::Ready(None),
+                Err(TryRecvError::Empty) => {},
+            }
+            self.chan.rx_waker.register(cx.waker());
+            match self.try_recv() {
+                Ok(value) => Poll::Ready(Some(value)),
+                Err(TryRecvError::Disconnected) => Poll::Ready(None),
+                Err(TryRecvError::Empty) => Poll::Pending,
+            }
+        }).await
+    }
+
+    pub fn try_recv(&mut self) -> Result<T, TryRecvError> {
+        if let Some(value) = self.chan.queue.lock().pop_front() {
+            self.chan.capacity.add_permits(1);
+            return Ok(value);
+        }
+        if self.chan.senders.load(Ordering::Acquire) == 0 {
+            // A value might have been pushed just before the last sender dropped
+            match self.chan.queue.lock().pop_front() {
+                Some(value) => Ok(value),
+                None => Err(TryRecvError::Disconnected),
+            }
+        } else {
+            Err(TryRecvError::Empty)
+        }
+    }
+
+    /// Stops the senders from sending any more values. Values already sent can still be received.
+    pub fn close(&mut self) {
+        let _queue = self.chan.queue.lock();
+        self.chan.capacity.close();
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        self.close();
+    }
+}
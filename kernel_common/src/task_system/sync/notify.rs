@@ -0,0 +1,151 @@
+use core::{
+    future::Future,
+    pin::Pin,
+    sync::atomic::{AtomicU64, Ordering},
+    task::{
+        Context,
+        Poll,
+        Waker,
+    },
+};
+use alloc::collections::VecDeque;
+use alloc::vec::Vec;
+
+/// Which call woke a waiter up. Only [`Notify::notify_one`] has to be passed on when the waiter
+/// goes away, as [`Notify::notify_waiters`] only ever wakes the tasks already waiting.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Notification {
+    One,
+    All,
+}
+
+struct Waiter {
+    id: u64,
+    waker: Option<Waker>,
+    notified: Option<Notification>,
+}
+
+struct NotifyState {
+    /// Set by `notify_one` when nobody was waiting, so the next `notified` completes right away.
+    permit: bool,
+    waiters: VecDeque<Waiter>,
+}
+
+/// Wakes up tasks waiting for an event.
+/// [`Notify::notify_one`] stores a permit if nobody is waiting, so a notification is never lost.
+pub struct Notify {
+    state: spin::Mutex<NotifyState>,
+    next_id: AtomicU64,
+}
+
+impl Notify {
+    pub const fn new() -> Self {
+        Self {
+            state: spin::Mutex::new(NotifyState {
+                permit: false,
+                waiters: VecDeque::new(),
+            }),
+            next_id: AtomicU64::new(0),
+        }
+    }
+
+    /// Waits for a notification.
+    pub fn notified(&self) -> Notified<'_> {
+        Notified {
+            notify: self,
+            id: None,
+        }
+    }
+
+    /// Wakes up the task that has been waiting the longest, or stores a permit if nobody is waiting.
+    pub fn notify_one(&self) {
+        let mut state = self.state.lock();
+        let waker = Self::notify_next(&mut state);
+        drop(state);
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+    }
+
+    fn notify_next(state: &mut NotifyState) -> Option<Waker> {
+        match state.waiters.iter_mut().find(|waiter| waiter.notified.is_none()) {
+            Some(waiter) => {
+                waiter.notified = Some(Notification::One);
+                waiter.waker.take()
+            },
+            None => {
+                state.permit = true;
+                None
+            },
+        }
+    }
+
+    /// Wakes up every task that is currently waiting. Does not store a permit.
+    pub fn notify_waiters(&self) {
+        let mut state = self.state.lock();
+        let wakers: Vec<Waker> = state.waiters.iter_mut()
+            .filter(|waiter| waiter.notified.is_none())
+            .filter_map(|waiter| {
+                waiter.notified = Some(Notification::All);
+                waiter.waker.take()
+            })
+            .collect();
+        drop(state);
+        for waker in wakers {
+            waker.wake();
+        }
+    }
+}
+
+/// Future returned by [`Notify::notified`].
+pub struct Notified<'a> {
+    notify: &'a Notify,
+    id: Option<u64>,
+}
+
+impl<'a> Future for Notified<'a> {
+    type Output = ();
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let notify = self.notify;
+        let mut state = notify.state.lock();
+        match self.id {
+            None => {
+                if state.permit {
+                    state.permit = false;
+                    return Poll::Ready(());
+                }
+                let id = notify.next_id.fetch_add(1, Ordering::Relaxed);
+                state.waiters.push_back(Waiter { id, waker: Some(cx.waker().clone()), notified: None });
+                drop(state);
+                self.id = Some(id);
+                Poll::Pending
+            },
+            Some(id) => {
+                let index = state.waiters.iter().position(|waiter| waiter.id == id).expect("Waiter missing from notify!");
+                if state.waiters[index].notified.is_some() {
+                    state.waiters.remove(index);
+                    drop(state);
+                    self.id = None;
+                    return Poll::Ready(());
+                }
+                state.waiters[index].waker = Some(cx.waker().clone());
+                Poll::Pending
+            },
+        }
+    }
+}
+
+impl<'a> Drop for Notified<'a> {
+    // A permit from `notify_one` we received but never consumed is passed on, so it isn't lost
+    fn drop(&mut self) {
+        let Some(id) = self.id else { return; };
+        let mut state = self.notify.state.lock();
+        let Some(index) = state.waiters.iter().position(|waiter| waiter.id == id) else { return; };
+        let waiter = state.waiters.remove(index).unwrap();
+        let waker = if waiter.notified == Some(Notification::One) { Notify::notify_next(&mut state) } else { None };
+        drop(state);
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+    }
+}
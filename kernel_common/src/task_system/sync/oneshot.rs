@@ -0,0 +1,113 @@
+//! A channel for sending a single value between tasks.
+
+use core::{
+    future::Future,
+    pin::Pin,
+    task::{
+        Context,
+        Poll,
+    },
+};
+use core::sync::atomic::{AtomicBool, Ordering};
+use alloc::sync::Arc;
+
+use crate::task_system::atomic_waker::AtomicWaker;
+
+/// The sender was dropped without sending a value.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RecvError;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TryRecvError {
+    Empty,
+    Closed,
+}
+
+struct Inner<T> {
+    value: spin::Mutex<Option<T>>,
+    /// Set once the value is sent, or the sender is dropped.
+    sender_done: AtomicBool,
+    receiver_dropped: AtomicBool,
+    rx_waker: AtomicWaker,
+}
+
+pub fn channel<T>() -> (Sender<T>, Receiver<T>) {
+    let inner = Arc::new(Inner {
+        value: spin::Mutex::new(None),
+        sender_done: AtomicBool::new(false),
+        receiver_dropped: AtomicBool::new(false),
+        rx_waker: AtomicWaker::new(),
+    });
+    (Sender { inner: Some(inner.clone()) }, Receiver { inner })
+}
+
+pub struct Sender<T> {
+    // Taken when sending, so drop knows not to close the channel
+    inner: Option<Arc<Inner<T>>>,
+}
+
+impl<T> Sender<T> {
+    /// Sends the value. Gives it back if the receiver is already gone.
+    pub fn send(mut self, value: T) -> Result<(), T> {
+        let inner = self.inner.take().unwrap();
+        if inner.receiver_dropped.load(Ordering::Acquire) {
+            return Err(value);
+        }
+        *inner.value.lock() = Some(value);
+        inner.sender_done.store(true, Ordering::Release);
+        inner.rx_waker.wake();
+        Ok(())
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.inner.as_ref().map(|inner| inner.receiver_dropped.load(Ordering::Acquire)).unwrap_or(true)
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        if let Some(inner) = self.inner.take() {
+            inner.sender_done.store(true, Ordering::Release);
+            inner.rx_waker.wake();
+        }
+    }
+}
+
+/// Awaiting the receiver gives the sent value.
+pub struct Receiver<T> {
+    inner: Arc<Inner<T>>,
+}
+
+impl<T> Receiver<T> {
+    pub fn try_recv(&mut self) -> Result<T, TryRecvError> {
+        // The value is stored before the sender is marked done, so check in the opposite order
+        if !self.inner.sender_done.load(Ordering::Acquire) {
+            return Err(TryRecvError::Empty);
+        }
+        self.inner.value.lock().take().ok_or(TryRecvError::Closed)
+    }
+}
+
+impl<T> Future for Receiver<T> {
+    type Output = Result<T, RecvError>;
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        match self.try_recv() {
+            Ok(value) => return Poll::Ready(Ok(value)),
+            Err(TryRecvError::Closed) => return Poll::Ready(Err(RecvError)),
+            Err(TryRecvError::Empty) => {},
+        }
+        self.inner.rx_waker.register(cx.waker());
+        // The value might have been sent while registering
+        match self.try_recv() {
+            Ok(value) => Poll::Ready(Ok(value)),
+            Err(TryRecvError::Closed) => Poll::Ready(Err(RecvError)),
+            Err(TryRecvError::Empty) => Poll::Pending,
+        }
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        self.inner.receiver_dropped.store(true, Ordering::Release);
+    }
+}
@@ -0,0 +1,100 @@
+use core::cell::UnsafeCell;
+use core::ops::{Deref, DerefMut};
+
+use super::semaphore::Semaphore;
+
+/// The most readers that can hold the lock at once. A writer takes all of them.
+const MAX_READERS: usize = u32::MAX as usize;
+
+/// An async reader-writer lock. Because waiters are served in order,
+/// a waiting writer blocks new readers, so writers can't be starved.
+pub struct RwLock<T: ?Sized> {
+    semaphore: Semaphore,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: ?Sized + Send> Send for RwLock<T> {}
+unsafe impl<T: ?Sized + Send + Sync> Sync for RwLock<T> {}
+
+impl<T> RwLock<T> {
+    pub const fn new(data: T) -> Self {
+        Self {
+            semaphore: Semaphore::new(MAX_READERS),
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.data.into_inner()
+    }
+}
+
+impl<T: ?Sized> RwLock<T> {
+    pub async fn read(&self) -> RwLockReadGuard<'_, T> {
+        self.semaphore.acquire().await.unwrap().forget();
+        RwLockReadGuard { lock: self }
+    }
+
+    pub async fn write(&self) -> RwLockWriteGuard<'_, T> {
+        self.semaphore.acquire_many(MAX_READERS).await.unwrap().forget();
+        RwLockWriteGuard { lock: self }
+    }
+
+    pub fn try_read(&self) -> Option<RwLockReadGuard<'_, T>> {
+        self.semaphore.try_acquire().ok()?.forget();
+        Some(RwLockReadGuard { lock: self })
+    }
+
+    pub fn try_write(&self) -> Option<RwLockWriteGuard<'_, T>> {
+        self.semaphore.try_acquire_many(MAX_READERS).ok()?.forget();
+        Some(RwLockWriteGuard { lock: self })
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        self.data.get_mut()
+    }
+}
+
+pub struct RwLockReadGuard<'a, T: ?Sized> {
+    lock: &'a RwLock<T>,
+}
+
+unsafe impl<'a, T: ?Sized + Sync> Sync for RwLockReadGuard<'a, T> {}
+
+impl<'a, T: ?Sized> Deref for RwLockReadGuard<'a, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<'a, T: ?Sized> Drop for RwLockReadGuard<'a, T> {
+    fn drop(&mut self) {
+        self.lock.semaphore.add_permits(1);
+    }
+}
+
+pub struct RwLockWriteGuard<'a, T: ?Sized> {
+    lock: &'a RwLock<T>,
+}
+
+unsafe impl<'a, T: ?Sized + Sync> Sync for RwLockWriteGuard<'a, T> {}
+
+impl<'a, T: ?Sized> Deref for RwLockWriteGuard<'a, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<'a, T: ?Sized> DerefMut for RwLockWriteGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
+
+impl<'a, T: ?Sized> Drop for RwLockWriteGuard<'a, T> {
+    fn drop(&mut self) {
+        self.lock.semaphore.add_permits(MAX_READERS);
+    }
+}
@@ -0,0 +1,226 @@
+use core::{
+    future::Future,
+    pin::Pin,
+    sync::atomic::{AtomicU64, Ordering},
+    task::{
+        Context,
+        Poll,
+        Waker,
+    },
+};
+use alloc::collections::VecDeque;
+use alloc::vec::Vec;
+
+/// The semaphore was closed while waiting.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AcquireError;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TryAcquireError {
+    Closed,
+    NoPermits,
+}
+
+struct Waiter {
+    id: u64,
+    permits: usize,
+    waker: Option<Waker>,
+    granted: bool,
+}
+
+struct SemaphoreState {
+    permits: usize,
+    /// Waiters are served in order, so a large acquire can't be starved by smaller ones.
+    waiters: VecDeque<Waiter>,
+    closed: bool,
+}
+
+impl SemaphoreState {
+    /// Hands permits to the waiters at the front of the queue, and returns their wakers.
+    fn grant(&mut self) -> Vec<Waker> {
+        let mut wakers = Vec::new();
+        for waiter in self.waiters.iter_mut() {
+            if waiter.granted {
+                continue;
+            }
+            if waiter.permits > self.permits {
+                break;
+            }
+            self.permits -= waiter.permits;
+            waiter.granted = true;
+            wakers.extend(waiter.waker.take());
+        }
+        wakers
+    }
+}
+
+/// A fair, async counting semaphore.
+pub struct Semaphore {
+    state: spin::Mutex<SemaphoreState>,
+    next_id: AtomicU64,
+}
+
+impl Semaphore {
+    pub const fn new(permits: usize) -> Self {
+        Self {
+            state: spin::Mutex::new(SemaphoreState {
+                permits,
+                waiters: VecDeque::new(),
+                closed: false,
+            }),
+            next_id: AtomicU64::new(0),
+        }
+    }
+
+    pub fn available_permits(&self) -> usize {
+        self.state.lock().permits
+    }
+
+    /// Waits for a single permit.
+    pub fn acquire(&self) -> Acquire<'_> {
+        self.acquire_many(1)
+    }
+
+    /// Waits until `permits` permits are available, and takes them all at once.
+    pub fn acquire_many(&self, permits: usize) -> Acquire<'_> {
+        Acquire {
+            semaphore: self,
+            permits,
+            id: None,
+        }
+    }
+
+    pub fn try_acquire(&self) -> Result<SemaphorePermit<'_>, TryAcquireError> {
+        self.try_acquire_many(1)
+    }
+
+    pub fn try_acquire_many(&self, permits: usize) -> Result<SemaphorePermit<'_>, TryAcquireError> {
+        let mut state = self.state.lock();
+        if state.closed {
+            return Err(TryAcquireError::Closed);
+        }
+        if !state.waiters.is_empty() || state.permits < permits {
+            return Err(TryAcquireError::NoPermits);
+        }
+        state.permits -= permits;
+        Ok(SemaphorePermit { semaphore: self, permits })
+    }
+
+    /// Returns permits to the semaphore, waking up waiters that can now be served.
+    pub fn add_permits(&self, permits: usize) {
+        let mut state = self.state.lock();
+        state.permits += permits;
+        let wakers = state.grant();
+        drop(state);
+        for waker in wakers {
+            waker.wake();
+        }
+    }
+
+    /// Closes the semaphore. Everyone waiting, and everyone who tries to acquire afterwards, gets an error.
+    pub fn close(&self) {
+        let mut state = self.state.lock();
+        state.closed = true;
+        let wakers: Vec<Waker> = state.waiters.iter_mut().filter_map(|waiter| waiter.waker.take()).collect();
+        drop(state);
+        for waker in wakers {
+            waker.wake();
+        }
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.state.lock().closed
+    }
+}
+
+/// Permits taken from a [`Semaphore`]. They are given back when this is dropped.
+pub struct SemaphorePermit<'a> {
+    semaphore: &'a Semaphore,
+    permits: usize,
+}
+
+impl<'a> SemaphorePermit<'a> {
+    /// Keeps the permits taken, instead of giving them back on drop.
+    pub fn forget(mut self) {
+        self.permits = 0;
+    }
+}
+
+impl<'a> Drop for SemaphorePermit<'a> {
+    fn drop(&mut self) {
+        if self.permits > 0 {
+            self.semaphore.add_permits(self.permits);
+        }
+    }
+}
+
+/// Future returned by [`Semaphore::acquire`].
+pub struct Acquire<'a> {
+    semaphore: &'a Semaphore,
+    permits: usize,
+    /// Set once we're in the queue of waiters.
+    id: Option<u64>,
+}
+
+impl<'a> Future for Acquire<'a> {
+    type Output = Result<SemaphorePermit<'a>, AcquireError>;
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let semaphore = self.semaphore;
+        let permits = self.permits;
+        let mut state = semaphore.state.lock();
+
+        match self.id {
+            None => {
+                if state.closed {
+                    return Poll::Ready(Err(AcquireError));
+                }
+                if state.waiters.is_empty() && state.permits >= permits {
+                    state.permits -= permits;
+                    return Poll::Ready(Ok(SemaphorePermit { semaphore, permits }));
+                }
+                let id = semaphore.next_id.fetch_add(1, Ordering::Relaxed);
+                state.waiters.push_back(Waiter { id, permits, waker: Some(cx.waker().clone()), granted: false });
+                drop(state);
+                self.id = Some(id);
+                Poll::Pending
+            },
+            Some(id) => {
+                let index = state.waiters.iter().position(|waiter| waiter.id == id).expect("Waiter missing from semaphore!");
+                if state.waiters[index].granted {
+                    state.waiters.remove(index);
+                    drop(state);
+                    self.id = None;
+                    return Poll::Ready(Ok(SemaphorePermit { semaphore, permits }));
+                }
+                if state.closed {
+                    state.waiters.remove(index);
+                    drop(state);
+                    self.id = None;
+                    return Poll::Ready(Err(AcquireError));
+                }
+                state.waiters[index].waker = Some(cx.waker().clone());
+                Poll::Pending
+            },
+        }
+    }
+}
+
+impl<'a> Drop for Acquire<'a> {
+    // Leaves the queue. If permits were already granted to us, they go to the next in line
+    fn drop(&mut self) {
+        let Some(id) = self.id else { return; };
+        let mut state = self.semaphore.state.lock();
+        if let Some(index) = state.waiters.iter().position(|waiter| waiter.id == id) {
+            let waiter = state.waiters.remove(index).unwrap();
+            if waiter.granted {
+                state.permits += waiter.permits;
+            }
+            // The waiter might have been blocking the ones behind it
+            let wakers = state.grant();
+            drop(state);
+            for waker in wakers {
+                waker.wake();
+            }
+        }
+    }
+}
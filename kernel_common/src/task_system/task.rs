@@ -8,35 +8,225 @@ use core::{
 };
 use alloc::{
     boxed::Box,
-    sync::Arc,
+    collections::BTreeMap,
+    string::String,
+    sync::{Arc, Weak},
+    vec::Vec,
 };
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use core::time::Duration;
 
 use futures_task::ArcWake;
 use super::executor::TaskQueue;
+use super::builder::TaskBuilder;
+use crate::time::Instant;
 
 pub type ArcTask = Arc<Task>;
 
+/// Unique identifier of a task. Never reused.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct TaskId(pub u64);
+
+impl TaskId {
+    fn next() -> Self {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+        Self(NEXT_ID.fetch_add(1, Ordering::Relaxed))
+    }
+}
+
+impl core::fmt::Display for TaskId {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// Priority classes, from highest to lowest. Every class has its own run queue,
+/// see [`RunQueue::pop`](super::executor::RunQueue::pop) for how they are picked.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Priority {
+    /// Drivers handling interrupts, like input. These should be quick.
+    Driver = 0,
+    /// Kernel services.
+    System = 1,
+    /// User programs, like WASM programs.
+    User = 2,
+    /// Work nobody is waiting for.
+    Background = 3,
+}
+
+impl Priority {
+    pub const COUNT: usize = 4;
+}
+
+/// Every task that is still alive, so they can be listed with [`tasks`].
+static TASKS: spin::Mutex<BTreeMap<TaskId, Weak<Task>>> = spin::Mutex::new(BTreeMap::new());
+
 pub struct Task {
+    id: TaskId,
+    name: Option<String>,
+    /// The core the task was spawned from.
+    spawn_core: usize,
+    created: Instant,
+    poll_count: AtomicU64,
+    /// Total time spent polling this task, in nanoseconds.
+    poll_time: AtomicU64,
     pub(crate) future: spin::Mutex<Option<Pin<Box< dyn Future<Output = ()> + Send >>>>,
-    task_queue: TaskQueue,
+    /// The queue of the executor that owns this task. Changes when the task gets stolen.
+    task_queue: spin::Mutex<TaskQueue>,
+    /// Pinned tasks always stay on the core they were spawned on.
+    pinned: bool,
+    priority: Priority,
+    /// Set by [`JoinHandle::abort`](super::join::JoinHandle::abort). The executor drops the future instead of polling it.
+    aborted: AtomicBool,
 }
 
 impl Task {
     pub fn new(future: impl Future<Output = ()> + Send + 'static, task_queue: TaskQueue) -> ArcTask {
-        Arc::new(Self {
+        Self::from_builder(TaskBuilder::new(), future, task_queue)
+    }
+
+    pub(crate) fn from_builder(builder: TaskBuilder, future: impl Future<Output = ()> + Send + 'static, task_queue: TaskQueue) -> ArcTask {
+        let task = Arc::new(Self {
+            id: TaskId::next(),
+            name: builder.task_name().map(String::from),
+            spawn_core: crate::platform::current_core(),
+            created: Instant::now(),
+            poll_count: AtomicU64::new(0),
+            poll_time: AtomicU64::new(0),
             future: spin::Mutex::new(Some(Box::pin(future))),
-            task_queue,
-        })
+            task_queue: spin::Mutex::new(task_queue),
+            pinned: builder.is_pinned(),
+            priority: builder.task_priority().unwrap_or_else(inherited_priority),
+            aborted: AtomicBool::new(false),
+        });
+        TASKS.lock().insert(task.id, Arc::downgrade(&task));
+        task
+    }
+
+    pub fn id(&self) -> TaskId {
+        self.id
+    }
+
+    pub fn name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+
+    /// Records a poll of the task. Called by the executor after every poll.
+    pub(crate) fn record_poll(&self, duration: Duration) {
+        self.poll_count.fetch_add(1, Ordering::Relaxed);
+        self.poll_time.fetch_add(duration.as_nanos() as u64, Ordering::Relaxed);
+    }
+
+    /// Returns a snapshot of the statistics of this task.
+    pub fn info(&self) -> TaskInfo {
+        TaskInfo {
+            id: self.id,
+            name: self.name.clone(),
+            core: self.core_id(),
+            spawn_core: self.spawn_core,
+            created: self.created,
+            poll_count: self.poll_count.load(Ordering::Relaxed),
+            poll_time: Duration::from_nanos(self.poll_time.load(Ordering::Relaxed)),
+            pinned: self.pinned,
+            priority: self.priority,
+        }
+    }
+
+    /// The core whose executor owns this task.
+    pub fn core_id(&self) -> usize {
+        self.task_queue.lock().core_id()
+    }
+
+    pub fn is_pinned(&self) -> bool {
+        self.pinned
+    }
+
+    pub fn priority(&self) -> Priority {
+        self.priority
+    }
+
+    /// Marks the task as aborted, and wakes it so its executor gets around to dropping it.
+    pub(crate) fn abort(self: &Arc<Self>) {
+        self.aborted.store(true, Ordering::Release);
+        Self::wake_by_ref(self);
+    }
+
+    pub fn is_aborted(&self) -> bool {
+        self.aborted.load(Ordering::Acquire)
+    }
+
+    /// Moves the task over to another executor. It is up to the caller to push it onto the new queue.
+    pub(crate) fn set_queue(&self, task_queue: TaskQueue) {
+        debug_assert!(!self.pinned, "Pinned tasks can't be moved!");
+        *self.task_queue.lock() = task_queue;
+    }
+}
+
+/// Tasks spawned from within another task get the priority of that task, so work a service
+/// does on behalf of a request runs at the priority of whoever made the request.
+/// Tasks spawned from outside any task run as [`Priority::System`].
+fn inherited_priority() -> Priority {
+    super::executor::current_task().map(|task| task.priority()).unwrap_or(Priority::System)
+}
+
+impl Drop for Task {
+    fn drop(&mut self) {
+        TASKS.lock().remove(&self.id);
     }
 }
 
 impl ArcWake for Task {
+    /// Puts the task back on the queue of its owning core.
+    /// If that core is halted, the queue sends it a wakeup IPI.
     fn wake_by_ref(arc_self: &Arc<Self>) {
         let cloned = arc_self.clone();
-        arc_self.task_queue.push(cloned);
+        let task_queue = arc_self.task_queue.lock().clone();
+        task_queue.push(cloned);
     }
 }
 
+/// A snapshot of the statistics of a task, see [`tasks`].
+#[derive(Debug, Clone)]
+pub struct TaskInfo {
+    pub id: TaskId,
+    pub name: Option<String>,
+    /// The core whose executor currently owns the task.
+    pub core: usize,
+    /// The core the task was spawned from.
+    pub spawn_core: usize,
+    pub created: Instant,
+    pub poll_count: u64,
+    /// Total time spent polling the task.
+    pub poll_time: Duration,
+    pub pinned: bool,
+    pub priority: Priority,
+}
+
+/// Returns a snapshot of every task that is alive, ordered by id.
+pub fn tasks() -> Vec<TaskInfo> {
+    // Upgraded first, so the lock isn't held while a task might be dropped
+    let alive: Vec<ArcTask> = TASKS.lock().values().filter_map(|task| task.upgrade()).collect();
+    alive.iter().map(|task| task.info()).collect()
+}
+
+/// Runs `f` with the given task, unless the task list is locked or the task is gone.
+/// Safe to call from interrupt handlers, as it never waits for the lock.
+pub fn try_with_task<T, F: FnOnce(&Task) -> T>(id: TaskId, f: F) -> Option<T> {
+    let tasks = TASKS.try_lock()?;
+    let task = tasks.get(&id)?.upgrade()?;
+    let result = f(&task);
+    drop(tasks);
+    // Can't be the last reference, as the executor is still polling it
+    drop(task);
+    Some(result)
+}
+
+/// Returns the statistics of a single task, if it is still alive.
+pub fn task_info(id: TaskId) -> Option<TaskInfo> {
+    let task = TASKS.lock().get(&id).and_then(|task| task.upgrade());
+    task.map(|task| task.info())
+}
+
 #[inline]
 pub async fn yield_now() {
     YieldNow(false).await
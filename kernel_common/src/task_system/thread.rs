@@ -0,0 +1,325 @@
+//! Kernel threads: stackful green threads that run as async tasks.
+//! A thread runs on its own stack, and can block on futures with [`ThreadContext::block_on`],
+//! which switches back to the executor until the future can make progress.
+//! This lets blocking-style code, like filesystem drivers or AML evaluation, run next to async tasks.
+//! A thread is spawned as a task, so its JoinHandle can be awaited like any other.
+//! ```ignore
+//! let handle = spawn_thread(|ctx| {
+//!     ctx.sleep(Duration::from_millis(10));
+//!     42
+//! })?;
+//! assert_eq!(handle.await, Ok(Ok(42)));
+//! ```
+//! A thread that overflows its stack or causes a CPU exception is killed, and finishes with
+//! a [`ThreadError`] instead of bringing down the kernel. It can't be unwound, so nothing on its stack
+//! gets dropped, and locks it held stay locked. The stack itself is freed.
+//! Only the stack of a thread that got aborted while blocked is leaked, as something might still point into it.
+
+use core::cell::RefCell;
+use core::future::Future;
+use core::pin::{Pin, pin};
+use core::task::{Context, Poll, Waker};
+use core::time::Duration;
+use core::sync::atomic::{AtomicPtr, AtomicU16, AtomicUsize, Ordering};
+use core::ptr;
+use alloc::sync::Arc;
+
+use fringe::{Generator, MappedStack, Stack, StackMapper, MapError};
+use fringe::generator::Yielder;
+
+use super::builder::TaskBuilder;
+use super::join::JoinHandle;
+use crate::platform::{platform, current_core};
+use crate::MAX_CPUS;
+
+/// Stack size of threads that don't set one in their [`TaskBuilder`].
+pub const DEFAULT_STACK_SIZE: usize = 64 * 1024;
+
+const PAGE_SIZE: usize = 4096;
+/// After a stack overflow or exception, the thread is switched back to the executor from this far above
+/// the limit of its stack. Only the deepest frames get overwritten, which are abandoned anyway.
+const OVERFLOW_RECOVERY_OFFSET: usize = 8 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ThreadError {
+    /// The thread ran out of stack, and was stopped.
+    StackOverflow,
+    /// The thread caused the CPU exception with this vector, and was killed.
+    Exception(u8),
+    /// There was no memory left for the stack of the thread, so it never started.
+    OutOfMemory,
+}
+
+impl ThreadError {
+    /// Packs the error into the `killed` field of [`ThreadShared`], where 0 means not killed.
+    fn to_raw(self) -> u16 {
+        match self {
+            ThreadError::StackOverflow => 1,
+            ThreadError::OutOfMemory => 2,
+            ThreadError::Exception(vector) => 0x100 | vector as u16,
+        }
+    }
+
+    fn from_raw(raw: u16) -> Option<Self> {
+        match raw {
+            0 => None,
+            1 => Some(ThreadError::StackOverflow),
+            2 => Some(ThreadError::OutOfMemory),
+            _ => Some(ThreadError::Exception(raw as u8)),
+        }
+    }
+}
+
+/// Maps the stacks of kernel threads with the kernel's stack allocator.
+#[derive(Debug, Clone, Copy)]
+pub struct KernelStackMapper;
+
+unsafe impl StackMapper for KernelStackMapper {
+    fn page_size(&self) -> usize {
+        PAGE_SIZE
+    }
+
+    // The kernel's stack allocator always leaves an unmapped page below a stack,
+    // which is included in the returned memory to become the guard page
+    unsafe fn map_stack(&self, len: usize) -> Result<*mut u8, MapError> {
+        let memory = platform().allocate_stack(len - PAGE_SIZE).ok_or(MapError)?;
+        Ok((memory.start - PAGE_SIZE) as *mut u8)
+    }
+
+    unsafe fn protect_stack(&self, _ptr: *mut u8) -> Result<(), MapError> {
+        // Never mapped in the first place
+        Ok(())
+    }
+
+    unsafe fn unmap_stack(&self, ptr: *mut u8, len: usize) -> Result<(), MapError> {
+        platform().free_stack(ptr as usize + PAGE_SIZE..ptr as usize + len);
+        Ok(())
+    }
+}
+
+/// The stack of a kernel thread. It has an unmapped guard page directly below it,
+/// so a thread overflowing its stack faults instead of corrupting memory.
+pub type ThreadStack = MappedStack<KernelStackMapper>;
+
+/// The thread gets resumed with the waker of the task, and yields whenever it is blocked.
+type ThreadGenerator = Generator<'static, Waker, (), ThreadStack>;
+
+/// Lets a kernel thread give control back to the executor.
+pub struct ThreadContext<'a> {
+    yielder: &'a Yielder<Waker, ()>,
+    /// The waker of the task the thread runs in, as of the last time it was resumed.
+    waker: RefCell<Waker>,
+}
+
+impl<'a> ThreadContext<'a> {
+    /// Switches back to the executor, until the task gets polled again.
+    fn suspend(&self) {
+        let waker = self.yielder.suspend(());
+        *self.waker.borrow_mut() = waker;
+    }
+
+    /// Lets the executor run other tasks, and continues afterwards.
+    pub fn yield_now(&self) {
+        self.waker.borrow().wake_by_ref();
+        self.suspend();
+    }
+
+    /// Runs a future to completion. While it can't make progress, the executor runs other tasks.
+    pub fn block_on<F: Future>(&self, future: F) -> F::Output {
+        let mut future = pin!(future);
+        loop {
+            let poll = future.as_mut().poll(&mut Context::from_waker(&self.waker.borrow()));
+            match poll {
+                Poll::Ready(output) => return output,
+                Poll::Pending => self.suspend(),
+            }
+        }
+    }
+
+    /// Blocks the thread for the given duration.
+    pub fn sleep(&self, duration: Duration) {
+        self.block_on(super::delay::sleep(duration));
+    }
+}
+
+/// The state of a thread that is shared with the exception handlers.
+struct ThreadShared {
+    /// The lowest usable address of the thread's stack.
+    limit: usize,
+    /// The address right above the thread's stack.
+    base: usize,
+    /// The yielder of the thread, once it started.
+    yielder: AtomicUsize,
+    /// Why the thread was killed, see [`ThreadError::to_raw`].
+    killed: AtomicU16,
+}
+
+impl ThreadShared {
+    fn killed(&self) -> Option<ThreadError> {
+        ThreadError::from_raw(self.killed.load(Ordering::Acquire))
+    }
+}
+
+const RUNNING_INIT: AtomicPtr<ThreadShared> = AtomicPtr::new(ptr::null_mut());
+/// The thread that is being resumed on every core, if any.
+static RUNNING: [AtomicPtr<ThreadShared>; MAX_CPUS] = [RUNNING_INIT; MAX_CPUS];
+
+/// Called by the double fault handler, when a kernel thread stack's guard page got hit.
+/// `limit` is the lowest usable address of the overflowing stack.
+/// If the stack belongs to the thread running on this core, returns the instruction pointer
+/// and stack pointer the thread should continue at. From there it switches back to the
+/// executor, and finishes with [`ThreadError::StackOverflow`].
+/// Returns None if the stack isn't that of the running thread, in which case the fault is fatal.
+/// Must only be called from the double fault handler, as it doesn't lock or allocate.
+pub fn stack_overflow_recovery(limit: usize) -> Option<(u64, u64)> {
+    let thread = running_thread()?;
+    if thread.limit != limit {
+        return None;
+    }
+    Some(kill(thread, ThreadError::StackOverflow))
+}
+
+/// Called by the exception handlers, when task code caused an exception with `vector`,
+/// while its stack pointer was at `stack_pointer`. The caller must make sure that the exception
+/// didn't happen in an interrupt handler, as those run on the stack of whatever they interrupted.
+/// If that is on the stack of the thread running on this core, the exception was caused by the thread.
+/// Returns the instruction pointer and stack pointer to continue at, from where the thread
+/// switches back to the executor, and finishes with [`ThreadError::Exception`].
+/// Returns None if no thread caused the exception, in which case it is fatal.
+/// Must only be called from exception handlers, as it doesn't lock or allocate.
+pub fn fault_recovery(stack_pointer: usize, vector: u8) -> Option<(u64, u64)> {
+    let thread = running_thread()?;
+    if !(thread.limit..thread.base).contains(&stack_pointer) {
+        return None;
+    }
+    Some(kill(thread, ThreadError::Exception(vector)))
+}
+
+/// Returns the thread running on this core, if it started already.
+fn running_thread() -> Option<&'static ThreadShared> {
+    let thread = RUNNING[current_core()].load(Ordering::Acquire);
+    // Safety: the pointer is only set while the KernelThread that owns it is being polled on this core
+    let thread = unsafe { thread.as_ref()? };
+    (thread.yielder.load(Ordering::Acquire) != 0).then_some(thread)
+}
+
+/// Marks the thread as killed, and returns where it has to continue to switch back to the executor.
+fn kill(thread: &ThreadShared, error: ThreadError) -> (u64, u64) {
+    thread.killed.store(error.to_raw(), Ordering::Release);
+    // Entered as if it was called, so the stack pointer is 8 off from a 16 byte boundary
+    let stack_pointer = ((thread.limit + OVERFLOW_RECOVERY_OFFSET) & !0xF) - 8;
+    (thread_killed as usize as u64, stack_pointer as u64)
+}
+
+/// Where a killed thread continues, on a part of its stack that is free again.
+/// It switches back to the executor for good.
+extern "C" fn thread_killed() -> ! {
+    let thread = RUNNING[current_core()].load(Ordering::Acquire);
+    // Safety: this is only ever entered from a thread that is being resumed on this core
+    let thread = unsafe { &*thread };
+    let yielder = unsafe { &*(thread.yielder.load(Ordering::Acquire) as *const Yielder<Waker, ()>) };
+    loop {
+        yielder.suspend(());
+    }
+}
+
+/// The future that runs a kernel thread. Every poll resumes the thread, until it blocks again.
+pub(crate) struct KernelThread<T> {
+    generator: Option<ThreadGenerator>,
+    output: Arc<spin::Mutex<Option<T>>>,
+    shared: Arc<ThreadShared>,
+}
+
+// Safety: the function run by the thread is Send, and everything else on the thread's stack
+// was created by it. The stack is kernel memory, so it is the same on every core,
+// and the thread moves between cores as a whole, like any other task.
+unsafe impl<T: Send> Send for KernelThread<T> {}
+
+impl<T: Send + 'static> KernelThread<T> {
+    /// Allocates the stack of the thread. It only starts running once polled.
+    pub(crate) fn new<F>(stack_size: usize, f: F) -> Result<Self, ThreadError>
+    where
+        F: FnOnce(&ThreadContext) -> T + Send + 'static,
+    {
+        let output = Arc::new(spin::Mutex::new(None));
+        let thread_output = output.clone();
+        let stack = ThreadStack::new(stack_size, KernelStackMapper).map_err(|_| ThreadError::OutOfMemory)?;
+        let shared = Arc::new(ThreadShared {
+            limit: stack.limit() as usize,
+            base: stack.base() as usize,
+            yielder: AtomicUsize::new(0),
+            killed: AtomicU16::new(0),
+        });
+        let thread_shared = shared.clone();
+        let generator = Generator::new(stack, move |yielder, waker| {
+            thread_shared.yielder.store(yielder as *const _ as usize, Ordering::Release);
+            drop(thread_shared);
+            let context = ThreadContext {
+                yielder,
+                waker: RefCell::new(waker),
+            };
+            let result = f(&context);
+            *thread_output.lock() = Some(result);
+        });
+        Ok(Self {
+            generator: Some(generator),
+            output,
+            shared,
+        })
+    }
+}
+
+impl<T> Future for KernelThread<T> {
+    type Output = Result<T, ThreadError>;
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = &mut *self;
+        let generator = this.generator.as_mut().expect("Kernel thread polled after completion!");
+        let running = &RUNNING[current_core()];
+        running.store(Arc::as_ptr(&this.shared) as *mut ThreadShared, Ordering::Release);
+        let resumed = generator.resume(cx.waker().clone());
+        running.store(ptr::null_mut(), Ordering::Release);
+        match resumed {
+            Some(()) => match this.shared.killed() {
+                Some(error) => {
+                    error!("Kernel thread was killed: {:?}", error);
+                    // Freed by the drop
+                    Poll::Ready(Err(error))
+                },
+                None => Poll::Pending,
+            },
+            None => {
+                // The thread returned, so dropping the generator frees its stack
+                this.generator = None;
+                let output = this.output.lock().take().expect("Kernel thread finished without output!");
+                Poll::Ready(Ok(output))
+            },
+        }
+    }
+}
+
+impl<T> Drop for KernelThread<T> {
+    // Happens when the task gets aborted, or the thread was killed. There is no unwinding in the kernel,
+    // so the values on the thread's stack can't be dropped either way.
+    fn drop(&mut self) {
+        let Some(generator) = self.generator.take() else { return; };
+        if self.shared.killed().is_some() {
+            // Parked in `thread_killed` for good. It was running when it got killed,
+            // not blocked on anything that could still point into its stack
+            drop(unsafe { generator.unsafe_unwrap() });
+        } else {
+            // Aborted while blocked, so something might still point into its stack
+            warn!("Kernel thread dropped before it finished, leaking its stack");
+            core::mem::forget(generator);
+        }
+    }
+}
+
+/// Spawns a kernel thread on the global scheduler. See [`TaskBuilder::spawn_thread`] for more options.
+/// Fails with [`ThreadError::OutOfMemory`] if its stack can't be allocated.
+pub fn spawn_thread<T, F>(f: F) -> Result<JoinHandle<Result<T, ThreadError>>, ThreadError>
+where
+    T: Send + 'static,
+    F: FnOnce(&ThreadContext) -> T + Send + 'static,
+{
+    TaskBuilder::new().spawn_thread(f)
+}
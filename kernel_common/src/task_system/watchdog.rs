@@ -0,0 +1,97 @@
+//! Watchdog for tasks that never yield.
+//! The executor marks when it starts and finishes polling a task, and the timer interrupt
+//! checks whether the poll on its core has been running for longer than the budget.
+//! A future that loops without ever returning `Pending` freezes its core, so this is the
+//! only way to find out which task is responsible, and where it is stuck.
+//! The timer interrupt can't log, as the interrupted task might be holding the logger's locks
+//! or allocating, so it reports straight to the serial port instead. If the poll ever returns,
+//! the executor logs how long it took in the end.
+
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use core::time::Duration;
+
+use crate::time::Instant;
+use crate::MAX_CPUS;
+use super::task::{try_with_task, Task, TaskId};
+
+/// How long a single poll may take before the watchdog reports it.
+pub const DEFAULT_BUDGET: Duration = Duration::from_millis(100);
+
+static BUDGET_NANOS: AtomicU64 = AtomicU64::new(DEFAULT_BUDGET.as_nanos() as u64);
+static PANIC_ON_TIMEOUT: AtomicBool = AtomicBool::new(cfg!(debug_assertions));
+
+struct PollSlot {
+    /// When the current poll started, in nanoseconds plus one. 0 while not polling.
+    started: AtomicU64,
+    task_id: AtomicU64,
+    /// Only report a poll once, not on every tick after the budget ran out.
+    reported: AtomicBool,
+}
+
+const SLOT_INIT: PollSlot = PollSlot {
+    started: AtomicU64::new(0),
+    task_id: AtomicU64::new(0),
+    reported: AtomicBool::new(false),
+};
+static SLOTS: [PollSlot; MAX_CPUS] = [SLOT_INIT; MAX_CPUS];
+
+/// Sets how long a single poll may take before the watchdog reports it.
+pub fn set_budget(budget: Duration) {
+    BUDGET_NANOS.store(budget.as_nanos() as u64, Ordering::Relaxed);
+}
+
+pub fn budget() -> Duration {
+    Duration::from_nanos(BUDGET_NANOS.load(Ordering::Relaxed))
+}
+
+/// Sets whether the watchdog panics instead of just reporting. On by default in debug builds.
+pub fn set_panic_on_timeout(panic: bool) {
+    PANIC_ON_TIMEOUT.store(panic, Ordering::Relaxed);
+}
+
+pub(crate) fn poll_started(core_id: usize, task_id: TaskId) {
+    let slot = &SLOTS[core_id];
+    slot.task_id.store(task_id.0, Ordering::Relaxed);
+    slot.reported.store(false, Ordering::Relaxed);
+    slot.started.store(Instant::now().as_nanos() + 1, Ordering::Release);
+}
+
+/// Ends the poll, and logs how long it took if the watchdog reported it.
+pub(crate) fn poll_finished(core_id: usize, task: &Task) {
+    let slot = &SLOTS[core_id];
+    let started = slot.started.swap(0, Ordering::AcqRel);
+    if !slot.reported.swap(false, Ordering::Relaxed) {
+        return;
+    }
+    let elapsed = Instant::now().as_nanos().saturating_sub(started - 1);
+    warn!("Watchdog: task {} ({}) yielded after being polled for {}ms on core {}",
+        task.id(), task.name().unwrap_or("<unnamed>"), elapsed / 1_000_000, core_id);
+}
+
+/// Called from the timer interrupt of every core, with the instruction pointer it interrupted.
+/// Reports a poll that ran out of budget to the serial port, and panics if the watchdog is set to.
+pub fn check(core_id: usize, rip: u64) {
+    let Some(slot) = SLOTS.get(core_id) else { return; };
+    let started = slot.started.load(Ordering::Acquire);
+    if started == 0 {
+        return;
+    }
+    let elapsed = Instant::now().as_nanos().saturating_sub(started - 1);
+    if elapsed <= BUDGET_NANOS.load(Ordering::Relaxed) || slot.reported.swap(true, Ordering::Relaxed) {
+        return;
+    }
+
+    let task_id = TaskId(slot.task_id.load(Ordering::Relaxed));
+    let report = |name: &str| crate::platform::platform().emergency_write(format_args!(
+        "\nWatchdog: task {} ({}) has been polled for {}ms on core {} without yielding, at RIP {:#x}\n",
+        task_id, name, elapsed / 1_000_000, core_id, rip));
+    // The task list is only tried, as the interrupted poll might be holding it
+    if try_with_task(task_id, |task| report(task.name().unwrap_or("<unnamed>"))).is_none() {
+        report("<unknown>");
+    }
+
+    // The panic handler doesn't take any locks, so it is fine to panic here
+    if PANIC_ON_TIMEOUT.load(Ordering::Relaxed) {
+        panic!("Watchdog: task {} has been polled for {}ms on core {} without yielding, at RIP {:#x}", task_id, elapsed / 1_000_000, core_id, rip);
+    }
+}
@@ -0,0 +1,138 @@
+//! Timekeeping. Until the kernel registers a clock source, time is based on the periodic timer interrupt.
+//! Once registered, [`Instant::now`] reads the clock source directly, which gives nanosecond
+//! resolution and does not take any locks.
+
+use core::ops::{Add, Sub};
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::time::Duration;
+
+use conquer_once::spin::OnceCell;
+
+/// The frequency the timer interrupt fires at on every core, in Hz.
+pub const TIMER_FREQUENCY_HZ: u64 = 1000;
+
+static TICKS: AtomicU64 = AtomicU64::new(0);
+
+/// Called by the timer interrupt handler on every core.
+/// Only the bootstrap core advances the global tick count, so the tick rate
+/// does not depend on how many cores there are.
+pub fn timer_tick(core_id: usize) {
+    if core_id == 0 {
+        TICKS.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Returns the amount of timer ticks since the timer was started.
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::Relaxed)
+}
+
+/// Returns the time since the timer was started, in milliseconds.
+pub fn uptime_ms() -> u64 {
+    ticks() * 1000 / TIMER_FREQUENCY_HZ
+}
+
+/// A free running counter that can be used as the time base.
+pub struct ClockSource {
+    pub name: &'static str,
+    /// Reads the counter. Must give the same result on every core, and must not take any locks.
+    pub read: fn() -> u64,
+    pub frequency_hz: u64,
+}
+
+struct ActiveClock {
+    source: ClockSource,
+    /// The counter value and time when the clock source was registered.
+    base_count: u64,
+    base_nanos: u64,
+    /// Nanoseconds per count, as a 32.32 fixed point number.
+    mult: u64,
+}
+
+impl ActiveClock {
+    fn nanos(&self) -> u64 {
+        let elapsed = (self.source.read)().wrapping_sub(self.base_count);
+        self.base_nanos + ((elapsed as u128 * self.mult as u128) >> 32) as u64
+    }
+}
+
+static CLOCK: OnceCell<ActiveClock> = OnceCell::uninit();
+
+/// Switches the time base over to `source`. Only the first registered clock source is used.
+/// Time continues from where the timer ticks left off, so instants stay monotonic.
+pub fn register_clock_source(source: ClockSource) {
+    assert!(source.frequency_hz > 0, "Clock source {} has no frequency!", source.name);
+    CLOCK.init_once(|| {
+        let mult = ((1_000_000_000u128 << 32) / source.frequency_hz as u128) as u64;
+        let base_nanos = tick_nanos();
+        let base_count = (source.read)();
+        ActiveClock { source, base_count, base_nanos, mult }
+    });
+}
+
+/// Returns the name of the clock source in use.
+pub fn clock_source_name() -> &'static str {
+    CLOCK.get().map(|clock| clock.source.name).unwrap_or("timer ticks")
+}
+
+fn tick_nanos() -> u64 {
+    ticks() * (1_000_000_000 / TIMER_FREQUENCY_HZ)
+}
+
+/// A point in time since the timer was started. Only meaningful when compared to other instants.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Instant {
+    nanos: u64,
+}
+
+impl Instant {
+    pub fn now() -> Self {
+        let nanos = match CLOCK.get() {
+            Some(clock) => clock.nanos(),
+            None => tick_nanos(),
+        };
+        Self { nanos }
+    }
+
+    /// Creates an instant from the amount of nanoseconds since the timer was started.
+    pub const fn from_nanos(nanos: u64) -> Self {
+        Self { nanos }
+    }
+
+    /// Returns the amount of nanoseconds since the timer was started.
+    pub const fn as_nanos(&self) -> u64 {
+        self.nanos
+    }
+
+    /// Returns the time elapsed from `earlier` to `self`, or zero if `earlier` is later.
+    pub fn saturating_duration_since(&self, earlier: Instant) -> Duration {
+        Duration::from_nanos(self.nanos.saturating_sub(earlier.nanos))
+    }
+
+    /// Returns the time elapsed since this instant.
+    pub fn elapsed(&self) -> Duration {
+        Instant::now().saturating_duration_since(*self)
+    }
+
+    pub fn checked_add(&self, duration: Duration) -> Option<Instant> {
+        let nanos = u64::try_from(duration.as_nanos()).ok()?;
+        self.nanos.checked_add(nanos).map(Instant::from_nanos)
+    }
+}
+
+impl Add<Duration> for Instant {
+    type Output = Instant;
+
+    /// Saturates instead of overflowing, so "forever" is simply the end of time.
+    fn add(self, duration: Duration) -> Instant {
+        self.checked_add(duration).unwrap_or(Instant::from_nanos(u64::MAX))
+    }
+}
+
+impl Sub<Instant> for Instant {
+    type Output = Duration;
+
+    fn sub(self, earlier: Instant) -> Duration {
+        self.saturating_duration_since(earlier)
+    }
+}
@@ -0,0 +1,146 @@
+//! The wall clock, which keeps track of the real date and time.
+//! The time is read once from the RTC during boot, and from then on advanced by the
+//! monotonic clock, so reading it is cheap and has nanosecond resolution.
+//! The monotonic clock drifts a little compared to the RTC, so [`drift_correction`]
+//! periodically compares the two, and slowly slews the wall clock back in line.
+//! Slewing happens in small steps, and reading the clock never returns an earlier time
+//! than the last read, so the wall clock never goes backwards unless it is set explicitly.
+
+use core::sync::atomic::{AtomicI64, AtomicU64, AtomicBool, Ordering};
+use core::time::Duration;
+use alloc::string::String;
+
+use crate::rtc;
+use crate::services::*;
+use crate::task_system::delay::sleep;
+use crate::time::Instant;
+
+const NANOS_PER_SEC: i64 = 1_000_000_000;
+/// How often the wall clock is compared to the RTC.
+const DRIFT_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+/// The RTC second boundary is found by polling it this often.
+const EDGE_POLL_INTERVAL: Duration = Duration::from_millis(1);
+/// The largest error that is slewed away. Anything beyond this many
+/// nanoseconds off is assumed to be a jump, and stepped instead.
+const MAX_SLEW_NANOS: i64 = NANOS_PER_SEC;
+/// How often a step of the correction is applied.
+const SLEW_STEP_INTERVAL: Duration = Duration::from_millis(100);
+/// The largest step, which limits the slew rate to 500ppm.
+const MAX_SLEW_STEP_NANOS: i64 = 50_000;
+
+/// UNIX time in nanoseconds minus the monotonic time in nanoseconds.
+static OFFSET_NANOS: AtomicI64 = AtomicI64::new(0);
+/// The latest time that was read, so a step backwards makes the clock stand still instead.
+static LAST_READ_NANOS: AtomicU64 = AtomicU64::new(0);
+static INITIALIZED: AtomicBool = AtomicBool::new(false);
+
+/// Reads the RTC and starts the wall clock from it.
+/// Should be called once the monotonic clock source is set up.
+pub fn init() {
+    let unix = rtc::rtc_time_seconds() as i64;
+    set_offset(unix * NANOS_PER_SEC);
+    INITIALIZED.store(true, Ordering::Release);
+    info!("Wall clock: {:?} UTC", rtc::unix_to_datetime(unix as u64));
+}
+
+/// Steps the wall clock to `unix_nanos`, which may be backwards.
+fn set_offset(unix_nanos: i64) {
+    OFFSET_NANOS.store(unix_nanos - Instant::now().as_nanos() as i64, Ordering::Release);
+    LAST_READ_NANOS.store(unix_nanos.max(0) as u64, Ordering::Release);
+}
+
+/// Returns whether the wall clock has been read from the RTC yet.
+pub fn is_initialized() -> bool {
+    INITIALIZED.load(Ordering::Acquire)
+}
+
+/// Returns the current time, in nanoseconds since the UNIX epoch.
+pub fn now_unix_nanos() -> u64 {
+    let now = (Instant::now().as_nanos() as i64 + OFFSET_NANOS.load(Ordering::Acquire)).max(0) as u64;
+    LAST_READ_NANOS.fetch_max(now, Ordering::AcqRel).max(now)
+}
+
+/// Returns the current time, in seconds since the UNIX epoch.
+pub fn now_unix_seconds() -> u64 {
+    now_unix_nanos() / NANOS_PER_SEC as u64
+}
+
+/// Returns the current date and time (in UTC).
+pub fn now() -> rtc::RtcTime {
+    rtc::unix_to_datetime(now_unix_seconds())
+}
+
+/// Sets the wall clock and the RTC to the given UNIX timestamp.
+pub fn set_time(unix_seconds: u64) {
+    rtc::set_rtc_time(&rtc::unix_to_datetime(unix_seconds));
+    set_offset(unix_seconds as i64 * NANOS_PER_SEC);
+    info!("Wall clock set to {:?} UTC", rtc::unix_to_datetime(unix_seconds));
+}
+
+/// Waits for the RTC seconds to tick over, and returns the new time in nanoseconds.
+/// At that moment the RTC is exact, up to the polling interval.
+async fn rtc_second_edge() -> i64 {
+    let start = rtc::rtc_time_seconds();
+    loop {
+        sleep(EDGE_POLL_INTERVAL).await;
+        let now = rtc::rtc_time_seconds();
+        if now != start {
+            return now as i64 * NANOS_PER_SEC;
+        }
+    }
+}
+
+/// Periodically corrects the wall clock for drift of the monotonic clock. Never returns.
+pub async fn drift_correction() {
+    // The part of the measured error that hasn't been slewed away yet
+    let mut pending: i64 = 0;
+    let mut next_check = Instant::now() + DRIFT_CHECK_INTERVAL;
+    loop {
+        sleep(SLEW_STEP_INTERVAL).await;
+        if Instant::now() >= next_check {
+            let rtc_nanos = rtc_second_edge().await;
+            next_check = Instant::now() + DRIFT_CHECK_INTERVAL;
+            let error = rtc_nanos - now_unix_nanos() as i64;
+            if error.abs() > MAX_SLEW_NANOS {
+                // Probably changed from outside, e.g. by the firmware, so just follow it
+                warn!("Wall clock was off by {}ms, stepping it", error / 1_000_000);
+                set_offset(rtc_nanos);
+                pending = 0;
+                continue;
+            }
+            // Whatever was still pending is part of the new measurement
+            pending = error;
+        }
+        let step = pending.clamp(-MAX_SLEW_STEP_NANOS, MAX_SLEW_STEP_NANOS);
+        if step != 0 {
+            OFFSET_NANOS.fetch_add(step, Ordering::AcqRel);
+            pending -= step;
+        }
+    }
+}
+
+/// A service that lets programs set the wall clock.
+pub struct WallClockService;
+
+impl Service for WallClockService {
+    fn name(&self) -> String { String::from("wall_clock") }
+    fn push_message(&self, message: ArcMessage) {
+        if let Some(msg) = message.as_any().downcast_ref::<SetWallClockMessage>() {
+            set_time(msg.unix_seconds);
+        }
+    }
+}
+
+pub struct SetWallClockMessage {
+    unix_seconds: u64,
+}
+
+impl SetWallClockMessage {
+    pub fn new(unix_seconds: u64) -> ArcMessage {
+        ArcMessage::new(alloc::boxed::Box::new(Self { unix_seconds }))
+    }
+}
+
+impl Message for SetWallClockMessage {
+    fn target(&self) -> &str { "wall_clock" }
+}
@@ -5,6 +5,7 @@ use anyhow::{Result, Error};
 use hashbrown::HashMap;
 
 use crate::Promise;
+use crate::task_system::thread::ThreadContext;
 
 pub struct ProgStorage {
     promises: Vec<Option<Promise>>,
@@ -61,12 +62,11 @@ pub struct WasmModule {
 }
 
 impl WasmModule {
-    /// Runs the module to completion
+    /// Runs the module to completion, on the stack of a kernel thread.
     /// Yields when calling a function returns a Resumable error
     /// NOTE: Out of fuel trap is not resumable! Only host errors are resumable
     ///       See: https://github.com/paritytech/wasmi/issues/696
-    pub async fn run(mut self) {
-        use crate::task_system::task::yield_now;
+    pub fn run(mut self, ctx: &ThreadContext) {
         let instance = self.instance.ensure_no_start(&mut self.store).expect("Failed to start instance!");
         let entry_point = instance.get_typed_func::<(), ()>(&self.store, "_start").expect("Failed to get `_start` function!");
         let values: [Value; 0] = [];
@@ -79,7 +79,7 @@ impl WasmModule {
                 }
                 return;
             } else {
-                yield_now().await;
+                ctx.yield_now();
                 // match self.store.consume_fuel(0) {
                 //     Ok(remaining_fuel) => {
                 //         // Make sure we are always above 1 million fuel
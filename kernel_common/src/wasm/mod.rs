@@ -3,6 +3,11 @@ mod abi_trait;
 pub mod abi;
 
 use backend::{WasmModule, ModuleBuilder};
+use crate::task_system::thread::{KernelThread, ThreadError};
+
+/// Every program is interpreted on its own stack of this size, as deep guest recursion
+/// or large interpreter frames would quickly overflow the stacks of the executors.
+pub const WASM_STACK_SIZE: usize = 1024 * 1024;
 
 pub struct WasmProgram {
     module: WasmModule,
@@ -21,7 +26,19 @@ impl WasmProgram {
         }
     }
 
+    /// Runs the program to completion on a dedicated kernel thread, switching back to
+    /// the executor whenever it yields. If it overflows its stack or faults, only this program is stopped.
     pub async fn run(self) {
-        self.module.run().await;
+        let module = self.module;
+        let result = match KernelThread::new(WASM_STACK_SIZE, move |ctx| module.run(ctx)) {
+            Ok(thread) => thread.await,
+            Err(e) => Err(e),
+        };
+        match result {
+            Ok(()) => {},
+            Err(ThreadError::OutOfMemory) => error!("Failed to start WASM program: no memory left for its stack"),
+            Err(ThreadError::StackOverflow) => error!("WASM trap encountered: program overflowed its stack"),
+            Err(ThreadError::Exception(vector)) => error!("WASM trap encountered: program caused exception {}", vector),
+        }
     }
 }
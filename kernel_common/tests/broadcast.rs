@@ -0,0 +1,114 @@
+mod common;
+
+use kernel_common::task_system::sync::broadcast::{self, RecvError, SendError, TryRecvError};
+
+use common::{ready, TestTask};
+
+#[test]
+fn every_receiver_sees_every_value_in_order() {
+    let (sender, mut first) = broadcast::channel(4);
+    let mut second = sender.subscribe();
+    assert_eq!(sender.send(1), Ok(2));
+    assert_eq!(sender.send(2), Ok(2));
+
+    assert_eq!(ready(first.recv()), Ok(1));
+    assert_eq!(ready(first.recv()), Ok(2));
+    assert_eq!(second.try_recv(), Ok(1));
+    assert_eq!(second.try_recv(), Ok(2));
+    assert_eq!(first.try_recv(), Err(TryRecvError::Empty));
+    assert_eq!(second.try_recv(), Err(TryRecvError::Empty));
+}
+
+#[test]
+fn subscribers_only_see_new_values() {
+    let (sender, _receiver) = broadcast::channel(4);
+    sender.send(1).unwrap();
+    let mut late = sender.subscribe();
+    assert_eq!(late.try_recv(), Err(TryRecvError::Empty));
+    sender.send(2).unwrap();
+    assert_eq!(late.try_recv(), Ok(2));
+}
+
+#[test]
+fn sending_wakes_every_receiver() {
+    let (sender, mut first) = broadcast::channel(4);
+    let mut second = sender.subscribe();
+    let mut first_recv = TestTask::new(first.recv());
+    let mut second_recv = TestTask::new(second.recv());
+    assert!(first_recv.is_pending());
+    assert!(second_recv.is_pending());
+
+    sender.send(1).unwrap();
+    assert!(first_recv.is_woken());
+    assert!(second_recv.is_woken());
+    assert_eq!(first_recv.unwrap_ready(), Ok(1));
+    assert_eq!(second_recv.unwrap_ready(), Ok(1));
+}
+
+#[test]
+fn slow_receiver_lags_behind() {
+    let (sender, mut receiver) = broadcast::channel(2);
+    for i in 0..5 {
+        sender.send(i).unwrap();
+    }
+    assert_eq!(ready(receiver.recv()), Err(RecvError::Lagged(3)));
+    assert_eq!(receiver.try_recv(), Ok(3));
+    assert_eq!(receiver.try_recv(), Ok(4));
+    assert_eq!(receiver.try_recv(), Err(TryRecvError::Empty));
+
+    sender.send(5).unwrap();
+    sender.send(6).unwrap();
+    sender.send(7).unwrap();
+    assert_eq!(receiver.try_recv(), Err(TryRecvError::Lagged(1)));
+    assert_eq!(receiver.try_recv(), Ok(6));
+}
+
+#[test]
+fn dropped_recv_keeps_the_receiver_usable() {
+    let (sender, mut receiver) = broadcast::channel(2);
+    let mut recv = TestTask::new(receiver.recv());
+    assert!(recv.is_pending());
+    drop(recv);
+
+    sender.send(1).unwrap();
+    assert_eq!(ready(receiver.recv()), Ok(1));
+}
+
+#[test]
+fn dropped_senders_close_the_channel() {
+    let (sender, mut receiver) = broadcast::channel(2);
+    let second_sender = sender.clone();
+    sender.send(1).unwrap();
+    drop(sender);
+    second_sender.send(2).unwrap();
+    drop(second_sender);
+
+    // Values sent before the last sender went away are still received
+    assert_eq!(receiver.try_recv(), Ok(1));
+    assert_eq!(ready(receiver.recv()), Ok(2));
+    assert_eq!(ready(receiver.recv()), Err(RecvError::Closed));
+    assert_eq!(receiver.try_recv(), Err(TryRecvError::Closed));
+}
+
+#[test]
+fn dropped_sender_wakes_the_receivers() {
+    let (sender, mut receiver) = broadcast::channel::<i32>(2);
+    let mut recv = TestTask::new(receiver.recv());
+    assert!(recv.is_pending());
+
+    drop(sender);
+    assert!(recv.is_woken());
+    assert_eq!(recv.unwrap_ready(), Err(RecvError::Closed));
+}
+
+#[test]
+fn send_fails_without_receivers() {
+    let (sender, receiver) = broadcast::channel(2);
+    let second = sender.subscribe();
+    assert_eq!(sender.receiver_count(), 2);
+    drop(receiver);
+    assert_eq!(sender.send(1), Ok(1));
+    drop(second);
+    assert_eq!(sender.receiver_count(), 0);
+    assert_eq!(sender.send(2), Err(SendError(2)));
+}
@@ -0,0 +1,69 @@
+//! Polls futures by hand, so tests can check exactly when a task gets woken.
+
+#![allow(dead_code)]
+
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::task::{Context, Poll, Wake, Waker};
+
+struct CountingWaker {
+    wakes: AtomicUsize,
+}
+
+impl Wake for CountingWaker {
+    fn wake(self: Arc<Self>) {
+        self.wake_by_ref();
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        self.wakes.fetch_add(1, Ordering::SeqCst);
+    }
+}
+
+/// A future with its own waker, standing in for a task.
+pub struct TestTask<F: Future> {
+    future: Pin<Box<F>>,
+    counter: Arc<CountingWaker>,
+    waker: Waker,
+}
+
+impl<F: Future> TestTask<F> {
+    pub fn new(future: F) -> Self {
+        let counter = Arc::new(CountingWaker { wakes: AtomicUsize::new(0) });
+        Self {
+            future: Box::pin(future),
+            waker: Waker::from(counter.clone()),
+            counter,
+        }
+    }
+
+    /// Polls the future once. Resets whether it was woken.
+    pub fn poll(&mut self) -> Poll<F::Output> {
+        self.counter.wakes.store(0, Ordering::SeqCst);
+        self.future.as_mut().poll(&mut Context::from_waker(&self.waker))
+    }
+
+    /// Polls the future, and panics if it isn't done.
+    pub fn unwrap_ready(&mut self) -> F::Output {
+        match self.poll() {
+            Poll::Ready(output) => output,
+            Poll::Pending => panic!("Future is still pending!"),
+        }
+    }
+
+    pub fn is_pending(&mut self) -> bool {
+        self.poll().is_pending()
+    }
+
+    /// Returns whether the future was woken since it was last polled.
+    pub fn is_woken(&self) -> bool {
+        self.counter.wakes.load(Ordering::SeqCst) > 0
+    }
+}
+
+/// Polls a future that is expected to complete right away.
+pub fn ready<F: Future>(future: F) -> F::Output {
+    TestTask::new(future).unwrap_ready()
+}
@@ -0,0 +1,142 @@
+mod common;
+
+use kernel_common::task_system::sync::mpsc::{self, SendError, TryRecvError, TrySendError};
+
+use common::{ready, TestTask};
+
+#[test]
+fn values_arrive_in_order() {
+    let (sender, mut receiver) = mpsc::channel(4);
+    let second_sender = sender.clone();
+    ready(sender.send(1)).unwrap();
+    second_sender.try_send(2).unwrap();
+    ready(sender.send(3)).unwrap();
+
+    assert_eq!(ready(receiver.recv()), Some(1));
+    assert_eq!(receiver.try_recv(), Ok(2));
+    assert_eq!(ready(receiver.recv()), Some(3));
+    assert_eq!(receiver.try_recv(), Err(TryRecvError::Empty));
+}
+
+#[test]
+fn sending_wakes_the_receiver() {
+    let (sender, mut receiver) = mpsc::channel(1);
+    let mut recv = TestTask::new(receiver.recv());
+    assert!(recv.is_pending());
+
+    sender.try_send(1).unwrap();
+    assert!(recv.is_woken());
+    assert_eq!(recv.unwrap_ready(), Some(1));
+}
+
+#[test]
+fn full_channel_makes_senders_wait_in_order() {
+    let (sender, mut receiver) = mpsc::channel(1);
+    sender.try_send(1).unwrap();
+    assert_eq!(sender.try_send(2), Err(TrySendError::Full(2)));
+
+    let mut first = TestTask::new(sender.send(2));
+    let mut second = TestTask::new(sender.send(3));
+    assert!(first.is_pending());
+    assert!(second.is_pending());
+
+    assert_eq!(receiver.try_recv(), Ok(1));
+    assert!(first.is_woken());
+    assert!(!second.is_woken());
+    first.unwrap_ready().unwrap();
+    assert!(second.is_pending());
+
+    assert_eq!(receiver.try_recv(), Ok(2));
+    assert!(second.is_woken());
+    second.unwrap_ready().unwrap();
+    assert_eq!(receiver.try_recv(), Ok(3));
+}
+
+#[test]
+fn dropped_send_gives_up_its_slot() {
+    let (sender, mut receiver) = mpsc::channel(1);
+    sender.try_send(1).unwrap();
+    let mut first = TestTask::new(sender.send(2));
+    let mut second = TestTask::new(sender.send(3));
+    assert!(first.is_pending());
+    assert!(second.is_pending());
+
+    // The slot is handed to the first send, which goes away before using it
+    assert_eq!(receiver.try_recv(), Ok(1));
+    drop(first);
+    assert!(second.is_woken());
+    second.unwrap_ready().unwrap();
+    assert_eq!(receiver.try_recv(), Ok(3));
+    assert_eq!(receiver.try_recv(), Err(TryRecvError::Empty));
+}
+
+#[test]
+fn dropped_senders_close_the_channel() {
+    let (sender, mut receiver) = mpsc::channel(2);
+    let second_sender = sender.clone();
+    sender.try_send(1).unwrap();
+    drop(sender);
+    assert_eq!(receiver.try_recv(), Ok(1));
+
+    let mut recv = TestTask::new(receiver.recv());
+    assert!(recv.is_pending());
+    second_sender.try_send(2).unwrap();
+    drop(second_sender);
+    // Values sent before the last sender went away are still received
+    assert_eq!(recv.unwrap_ready(), Some(2));
+    drop(recv);
+    assert_eq!(ready(receiver.recv()), None);
+    assert_eq!(receiver.try_recv(), Err(TryRecvError::Disconnected));
+}
+
+#[test]
+fn dropped_sender_wakes_the_receiver() {
+    let (sender, mut receiver) = mpsc::channel::<i32>(1);
+    let mut recv = TestTask::new(receiver.recv());
+    assert!(recv.is_pending());
+
+    drop(sender);
+    assert!(recv.is_woken());
+    assert_eq!(recv.unwrap_ready(), None);
+}
+
+#[test]
+fn dropped_receiver_closes_the_channel() {
+    let (sender, receiver) = mpsc::channel(1);
+    sender.try_send(1).unwrap();
+    let mut send = TestTask::new(sender.send(2));
+    assert!(send.is_pending());
+
+    drop(receiver);
+    assert!(sender.is_closed());
+    assert!(send.is_woken());
+    assert_eq!(send.unwrap_ready(), Err(SendError(2)));
+    assert_eq!(sender.try_send(3), Err(TrySendError::Closed(3)));
+    assert_eq!(ready(sender.send(4)), Err(SendError(4)));
+}
+
+#[test]
+fn receiver_dropped_after_a_slot_was_granted() {
+    let (sender, mut receiver) = mpsc::channel(1);
+    sender.try_send(1).unwrap();
+    let mut send = TestTask::new(sender.send(2));
+    assert!(send.is_pending());
+
+    // Frees the slot for the pending send, which only gets to use it after the receiver is gone
+    assert_eq!(receiver.try_recv(), Ok(1));
+    assert!(send.is_woken());
+    drop(receiver);
+    assert_eq!(send.unwrap_ready(), Err(SendError(2)));
+}
+
+#[test]
+fn closed_receiver_still_gets_sent_values() {
+    let (sender, mut receiver) = mpsc::channel(2);
+    sender.try_send(1).unwrap();
+    receiver.close();
+    assert_eq!(sender.try_send(2), Err(TrySendError::Closed(2)));
+
+    assert_eq!(receiver.try_recv(), Ok(1));
+    drop(sender);
+    assert_eq!(receiver.try_recv(), Err(TryRecvError::Disconnected));
+}
@@ -0,0 +1,85 @@
+mod common;
+
+use kernel_common::task_system::sync::Mutex;
+
+use common::{ready, TestTask};
+
+#[test]
+fn lock_is_exclusive() {
+    let mutex = Mutex::new(0);
+    let mut guard = ready(mutex.lock());
+    *guard += 1;
+    assert!(mutex.try_lock().is_none());
+    drop(guard);
+
+    assert_eq!(*mutex.try_lock().unwrap(), 1);
+    assert_eq!(mutex.into_inner(), 1);
+}
+
+#[test]
+fn waiters_get_the_lock_in_order() {
+    let mutex = Mutex::new(Vec::new());
+    let guard = mutex.try_lock().unwrap();
+    let mut first = TestTask::new(async {
+        mutex.lock().await.push(1);
+    });
+    let mut second = TestTask::new(async {
+        mutex.lock().await.push(2);
+    });
+    assert!(first.is_pending());
+    assert!(second.is_pending());
+
+    drop(guard);
+    assert!(first.is_woken());
+    assert!(!second.is_woken());
+    first.unwrap_ready();
+    // The first waiter unlocked again when it was done
+    assert!(second.is_woken());
+    second.unwrap_ready();
+
+    assert_eq!(*mutex.try_lock().unwrap(), [1, 2]);
+}
+
+#[test]
+fn try_lock_does_not_jump_the_queue() {
+    let mutex = Mutex::new(());
+    let guard = mutex.try_lock().unwrap();
+    let mut waiter = TestTask::new(mutex.lock());
+    assert!(waiter.is_pending());
+
+    drop(guard);
+    assert!(mutex.try_lock().is_none());
+    waiter.unwrap_ready();
+}
+
+#[test]
+fn dropped_waiter_leaves_the_queue() {
+    let mutex = Mutex::new(());
+    let guard = mutex.try_lock().unwrap();
+    let mut first = TestTask::new(mutex.lock());
+    let mut second = TestTask::new(mutex.lock());
+    assert!(first.is_pending());
+    assert!(second.is_pending());
+
+    drop(first);
+    drop(guard);
+    assert!(second.is_woken());
+    second.unwrap_ready();
+}
+
+#[test]
+fn dropped_waiter_passes_on_the_lock() {
+    let mutex = Mutex::new(());
+    let guard = mutex.try_lock().unwrap();
+    let mut first = TestTask::new(mutex.lock());
+    let mut second = TestTask::new(mutex.lock());
+    assert!(first.is_pending());
+    assert!(second.is_pending());
+
+    // The lock is handed to the first waiter, which goes away before taking it
+    drop(guard);
+    assert!(first.is_woken());
+    drop(first);
+    assert!(second.is_woken());
+    second.unwrap_ready();
+}
@@ -0,0 +1,118 @@
+mod common;
+
+use kernel_common::task_system::sync::Notify;
+
+use common::{ready, TestTask};
+
+#[test]
+fn notify_one_stores_a_permit() {
+    let notify = Notify::new();
+    notify.notify_one();
+    ready(notify.notified());
+
+    // The permit was used up
+    let mut waiter = TestTask::new(notify.notified());
+    assert!(waiter.is_pending());
+}
+
+#[test]
+fn permits_do_not_add_up() {
+    let notify = Notify::new();
+    notify.notify_one();
+    notify.notify_one();
+    ready(notify.notified());
+    assert!(TestTask::new(notify.notified()).is_pending());
+}
+
+#[test]
+fn notify_one_wakes_waiters_in_order() {
+    let notify = Notify::new();
+    let mut first = TestTask::new(notify.notified());
+    let mut second = TestTask::new(notify.notified());
+    assert!(first.is_pending());
+    assert!(second.is_pending());
+
+    notify.notify_one();
+    assert!(first.is_woken());
+    assert!(!second.is_woken());
+    first.unwrap_ready();
+    assert!(second.is_pending());
+
+    notify.notify_one();
+    assert!(second.is_woken());
+    second.unwrap_ready();
+}
+
+#[test]
+fn notify_waiters_wakes_everyone_waiting() {
+    let notify = Notify::new();
+    let mut first = TestTask::new(notify.notified());
+    let mut second = TestTask::new(notify.notified());
+    assert!(first.is_pending());
+    assert!(second.is_pending());
+
+    notify.notify_waiters();
+    assert!(first.is_woken());
+    assert!(second.is_woken());
+    first.unwrap_ready();
+    second.unwrap_ready();
+}
+
+#[test]
+fn notify_waiters_does_not_store_a_permit() {
+    let notify = Notify::new();
+    notify.notify_waiters();
+    assert!(TestTask::new(notify.notified()).is_pending());
+}
+
+#[test]
+fn dropped_waiter_is_skipped() {
+    let notify = Notify::new();
+    let mut first = TestTask::new(notify.notified());
+    let mut second = TestTask::new(notify.notified());
+    assert!(first.is_pending());
+    assert!(second.is_pending());
+
+    drop(first);
+    notify.notify_one();
+    assert!(second.is_woken());
+    second.unwrap_ready();
+}
+
+#[test]
+fn dropped_waiter_passes_on_notify_one() {
+    let notify = Notify::new();
+    let mut first = TestTask::new(notify.notified());
+    let mut second = TestTask::new(notify.notified());
+    assert!(first.is_pending());
+    assert!(second.is_pending());
+
+    notify.notify_one();
+    assert!(first.is_woken());
+    drop(first);
+    assert!(second.is_woken());
+    second.unwrap_ready();
+}
+
+#[test]
+fn dropped_waiter_stores_notify_one_as_a_permit() {
+    let notify = Notify::new();
+    let mut waiter = TestTask::new(notify.notified());
+    assert!(waiter.is_pending());
+
+    notify.notify_one();
+    drop(waiter);
+    ready(notify.notified());
+}
+
+#[test]
+fn dropped_waiter_does_not_pass_on_notify_waiters() {
+    let notify = Notify::new();
+    let mut waiter = TestTask::new(notify.notified());
+    assert!(waiter.is_pending());
+
+    notify.notify_waiters();
+    assert!(waiter.is_woken());
+    drop(waiter);
+    assert!(TestTask::new(notify.notified()).is_pending());
+}
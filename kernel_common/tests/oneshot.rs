@@ -0,0 +1,62 @@
+mod common;
+
+use kernel_common::task_system::sync::oneshot::{self, RecvError, TryRecvError};
+
+use common::{ready, TestTask};
+
+#[test]
+fn value_sent_before_receiving() {
+    let (sender, receiver) = oneshot::channel();
+    sender.send(1).unwrap();
+    assert_eq!(ready(receiver), Ok(1));
+}
+
+#[test]
+fn sending_wakes_the_receiver() {
+    let (sender, receiver) = oneshot::channel();
+    let mut receiver = TestTask::new(receiver);
+    assert!(receiver.is_pending());
+
+    sender.send(1).unwrap();
+    assert!(receiver.is_woken());
+    assert_eq!(receiver.unwrap_ready(), Ok(1));
+}
+
+#[test]
+fn try_recv() {
+    let (sender, mut receiver) = oneshot::channel();
+    assert_eq!(receiver.try_recv(), Err(TryRecvError::Empty));
+    sender.send(1).unwrap();
+    assert_eq!(receiver.try_recv(), Ok(1));
+    assert_eq!(receiver.try_recv(), Err(TryRecvError::Closed));
+}
+
+#[test]
+fn dropped_sender_closes_the_channel() {
+    let (sender, receiver) = oneshot::channel::<i32>();
+    let mut receiver = TestTask::new(receiver);
+    assert!(receiver.is_pending());
+
+    drop(sender);
+    assert!(receiver.is_woken());
+    assert_eq!(receiver.unwrap_ready(), Err(RecvError));
+}
+
+#[test]
+fn dropped_receiver_gives_the_value_back() {
+    let (sender, receiver) = oneshot::channel();
+    assert!(!sender.is_closed());
+    drop(receiver);
+    assert!(sender.is_closed());
+    assert_eq!(sender.send(1), Err(1));
+}
+
+#[test]
+fn dropped_pending_receiver_closes_the_channel() {
+    let (sender, receiver) = oneshot::channel();
+    let mut receiver = TestTask::new(receiver);
+    assert!(receiver.is_pending());
+
+    drop(receiver);
+    assert_eq!(sender.send(1), Err(1));
+}
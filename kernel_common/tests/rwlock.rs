@@ -0,0 +1,72 @@
+mod common;
+
+use kernel_common::task_system::sync::RwLock;
+
+use common::{ready, TestTask};
+
+#[test]
+fn readers_share_the_lock() {
+    let lock = RwLock::new(1);
+    let first = ready(lock.read());
+    let second = lock.try_read().unwrap();
+    assert_eq!(*first + *second, 2);
+    assert!(lock.try_write().is_none());
+    drop(first);
+    assert!(lock.try_write().is_none());
+    drop(second);
+
+    *lock.try_write().unwrap() = 3;
+    assert_eq!(lock.into_inner(), 3);
+}
+
+#[test]
+fn writer_is_exclusive() {
+    let lock = RwLock::new(());
+    let writer = ready(lock.write());
+    assert!(lock.try_read().is_none());
+    assert!(lock.try_write().is_none());
+
+    let mut reader = TestTask::new(lock.read());
+    assert!(reader.is_pending());
+    drop(writer);
+    assert!(reader.is_woken());
+    reader.unwrap_ready();
+}
+
+#[test]
+fn waiting_writer_blocks_new_readers() {
+    let lock = RwLock::new(());
+    let reader = lock.try_read().unwrap();
+    let mut writer = TestTask::new(lock.write());
+    assert!(writer.is_pending());
+
+    // Served in order, so the reader queues up behind the writer
+    let mut late_reader = TestTask::new(lock.read());
+    assert!(late_reader.is_pending());
+    assert!(lock.try_read().is_none());
+
+    drop(reader);
+    assert!(writer.is_woken());
+    assert!(!late_reader.is_woken());
+    let write_guard = writer.unwrap_ready();
+    assert!(late_reader.is_pending());
+
+    drop(write_guard);
+    assert!(late_reader.is_woken());
+    late_reader.unwrap_ready();
+}
+
+#[test]
+fn dropped_writer_unblocks_the_readers_behind_it() {
+    let lock = RwLock::new(());
+    let reader = lock.try_read().unwrap();
+    let mut writer = TestTask::new(lock.write());
+    let mut late_reader = TestTask::new(lock.read());
+    assert!(writer.is_pending());
+    assert!(late_reader.is_pending());
+
+    drop(writer);
+    assert!(late_reader.is_woken());
+    late_reader.unwrap_ready();
+    drop(reader);
+}
@@ -0,0 +1,117 @@
+mod common;
+
+use kernel_common::task_system::sync::{AcquireError, Semaphore, TryAcquireError};
+
+use common::{ready, TestTask};
+
+#[test]
+fn permits_are_returned_on_drop() {
+    let semaphore = Semaphore::new(2);
+    let permit = semaphore.try_acquire_many(2).unwrap();
+    assert_eq!(semaphore.available_permits(), 0);
+    assert_eq!(semaphore.try_acquire().err(), Some(TryAcquireError::NoPermits));
+    drop(permit);
+    assert_eq!(semaphore.available_permits(), 2);
+
+    ready(semaphore.acquire()).unwrap().forget();
+    assert_eq!(semaphore.available_permits(), 1);
+}
+
+#[test]
+fn waiters_are_served_in_order() {
+    let semaphore = Semaphore::new(0);
+    let mut first = TestTask::new(semaphore.acquire());
+    let mut second = TestTask::new(semaphore.acquire());
+    assert!(first.is_pending());
+    assert!(second.is_pending());
+
+    semaphore.add_permits(1);
+    assert!(first.is_woken());
+    assert!(!second.is_woken());
+    let permit = first.unwrap_ready().unwrap();
+    assert!(second.is_pending());
+
+    drop(permit);
+    assert!(second.is_woken());
+    second.unwrap_ready().unwrap();
+}
+
+#[test]
+fn large_acquire_is_not_starved() {
+    let semaphore = Semaphore::new(1);
+    let mut large = TestTask::new(semaphore.acquire_many(2));
+    assert!(large.is_pending());
+
+    // A permit is available, but the large acquire is first in line
+    let mut small = TestTask::new(semaphore.acquire());
+    assert!(small.is_pending());
+    assert_eq!(semaphore.try_acquire().err(), Some(TryAcquireError::NoPermits));
+
+    semaphore.add_permits(1);
+    assert!(large.is_woken());
+    assert!(!small.is_woken());
+    let permit = large.unwrap_ready().unwrap();
+    assert!(small.is_pending());
+
+    drop(permit);
+    small.unwrap_ready().unwrap();
+}
+
+#[test]
+fn dropped_waiter_leaves_the_queue() {
+    let semaphore = Semaphore::new(0);
+    let mut first = TestTask::new(semaphore.acquire());
+    let mut second = TestTask::new(semaphore.acquire());
+    assert!(first.is_pending());
+    assert!(second.is_pending());
+
+    drop(first);
+    semaphore.add_permits(1);
+    assert!(second.is_woken());
+    second.unwrap_ready().unwrap();
+}
+
+#[test]
+fn dropped_waiter_passes_on_granted_permits() {
+    let semaphore = Semaphore::new(0);
+    let mut first = TestTask::new(semaphore.acquire());
+    let mut second = TestTask::new(semaphore.acquire());
+    assert!(first.is_pending());
+    assert!(second.is_pending());
+
+    semaphore.add_permits(1);
+    assert!(first.is_woken());
+    drop(first);
+    assert!(second.is_woken());
+    second.unwrap_ready().unwrap().forget();
+    assert_eq!(semaphore.available_permits(), 0);
+}
+
+#[test]
+fn dropped_large_waiter_unblocks_the_ones_behind_it() {
+    let semaphore = Semaphore::new(1);
+    let mut large = TestTask::new(semaphore.acquire_many(2));
+    let mut small = TestTask::new(semaphore.acquire());
+    assert!(large.is_pending());
+    assert!(small.is_pending());
+
+    drop(large);
+    assert!(small.is_woken());
+    small.unwrap_ready().unwrap();
+}
+
+#[test]
+fn close_wakes_waiters_with_an_error() {
+    let semaphore = Semaphore::new(0);
+    let mut waiter = TestTask::new(semaphore.acquire());
+    assert!(waiter.is_pending());
+
+    semaphore.close();
+    assert!(semaphore.is_closed());
+    assert!(waiter.is_woken());
+    assert_eq!(waiter.unwrap_ready().err(), Some(AcquireError));
+
+    semaphore.add_permits(1);
+    assert_eq!(ready(semaphore.acquire()).err(), Some(AcquireError));
+    assert_eq!(semaphore.try_acquire().err(), Some(TryAcquireError::Closed));
+}
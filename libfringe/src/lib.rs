@@ -23,7 +23,10 @@
 //!   * a stack allocator based on `Box<[u8]>`,
 //!     [OwnedStack](struct.OwnedStack.html);
 //!   * a stack allocator based on anonymous memory mappings with guard pages,
-//!     [OsStack](struct.OsStack.html).
+//!     [OsStack](struct.OsStack.html);
+//!   * a stack allocator with guard pages for freestanding targets,
+//!     [MappedStack](struct.MappedStack.html), whose pages are mapped by
+//!     a [StackMapper](trait.StackMapper.html).
 
 #![feature(naked_functions)]
 #![cfg_attr(test, feature(test))]
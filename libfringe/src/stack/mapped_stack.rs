@@ -0,0 +1,112 @@
+// This file is part of libfringe, a low-level green threading library.
+// Copyright (c) edef <edef@edef.eu>
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+use core::fmt;
+
+use crate::stack::{GuardedStack, Stack, MIN_STACK_SIZE};
+
+/// The error returned by a `StackMapper` that could not map or protect memory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MapError;
+
+/// A hook that manages the memory of a `MappedStack`, for environments without an OS,
+/// e.g. a kernel that maps the pages itself.
+///
+/// To preserve memory safety, an implementation of this trait must fulfill
+/// the following contract:
+///
+///   * `page_size` must always return the same power of two.
+///   * `map_stack(len)` must return a page-aligned pointer to `len` bytes of
+///     readable and writable memory, that stays mapped until `unmap_stack`.
+///   * Once `protect_stack(ptr)` succeeds, any access of data in the page at `ptr`
+///     must abnormally terminate, at least, the thread that performs the access.
+pub unsafe trait StackMapper {
+  /// Returns the size of a page. Stacks and guard pages are a whole number of pages.
+  fn page_size(&self) -> usize;
+
+  /// Maps `len` bytes of memory for a stack. `len` is a multiple of the page size.
+  unsafe fn map_stack(&self, len: usize) -> Result<*mut u8, MapError>;
+
+  /// Turns the page at `ptr`, the lowest page returned by `map_stack`, into a guard page.
+  unsafe fn protect_stack(&self, ptr: *mut u8) -> Result<(), MapError>;
+
+  /// Unmaps memory returned by `map_stack`.
+  unsafe fn unmap_stack(&self, ptr: *mut u8, len: usize) -> Result<(), MapError>;
+}
+
+/// A stack whose memory is mapped by a `StackMapper`, with a guard page below it.
+/// This is the equivalent of `OsStack` for freestanding targets.
+pub struct MappedStack<M: StackMapper> {
+  ptr: *mut u8,
+  len: usize,
+  mapper: M,
+}
+
+unsafe impl<M: StackMapper + Send> Send for MappedStack<M> {}
+
+impl<M: StackMapper> MappedStack<M> {
+  /// Allocates a new stack with at least `size` accessible bytes, using `mapper`.
+  /// `size` is rounded up to an integral number of pages; `MappedStack::new(0, mapper)`
+  /// is legal and allocates the smallest possible stack, consisting of
+  /// `MIN_STACK_SIZE` bytes of data pages and one guard page.
+  pub fn new(size: usize, mapper: M) -> Result<MappedStack<M>, MapError> {
+    let page_size = mapper.page_size();
+    assert!(page_size.is_power_of_two(), "page size must be a power of two");
+    let len = core::cmp::max(MIN_STACK_SIZE, size);
+
+    // Round the length one page size up, using the fact that the page size
+    // is a power of two.
+    let len = (len + page_size - 1) & !(page_size - 1);
+
+    // Increase the length to fit the guard page.
+    let len = len + page_size;
+
+    // Allocate a stack.
+    let ptr = unsafe { mapper.map_stack(len)? };
+    let stack = MappedStack { ptr, len, mapper };
+
+    // Mark the guard page. If this fails, `stack` will be dropped,
+    // unmapping it.
+    unsafe { stack.mapper.protect_stack(stack.ptr)? };
+
+    Ok(stack)
+  }
+
+  /// Returns the mapper that manages the memory of this stack.
+  pub fn mapper(&self) -> &M {
+    &self.mapper
+  }
+}
+
+impl<M: StackMapper> fmt::Debug for MappedStack<M> {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    f.debug_struct("MappedStack")
+      .field("ptr", &self.ptr)
+      .field("len", &self.len)
+      .finish()
+  }
+}
+
+unsafe impl<M: StackMapper> Stack for MappedStack<M> {
+  #[inline(always)]
+  fn base(&self) -> *mut u8 {
+    unsafe { self.ptr.add(self.len) }
+  }
+
+  #[inline(always)]
+  fn limit(&self) -> *mut u8 {
+    unsafe { self.ptr.add(self.mapper.page_size()) }
+  }
+}
+
+unsafe impl<M: StackMapper> GuardedStack for MappedStack<M> {}
+
+impl<M: StackMapper> Drop for MappedStack<M> {
+  fn drop(&mut self) {
+    unsafe { self.mapper.unmap_stack(self.ptr, self.len) }.expect("cannot unmap stack")
+  }
+}
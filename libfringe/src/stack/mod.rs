@@ -16,6 +16,9 @@ mod owned_stack;
 #[cfg(feature = "alloc")]
 pub use crate::stack::owned_stack::OwnedStack;
 
+mod mapped_stack;
+pub use crate::stack::mapped_stack::{MapError, MappedStack, StackMapper};
+
 #[cfg(all(unix, feature = "std"))]
 mod os;
 #[cfg(all(unix, feature = "std"))]
@@ -0,0 +1,142 @@
+// This file is part of libfringe, a low-level green threading library.
+// Copyright (c) edef <edef@edef.eu>
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+extern crate alloc;
+
+use alloc::alloc::{alloc, dealloc};
+use std::{alloc::Layout, cell::RefCell, rc::Rc};
+
+use fringe::{
+  generator::Generator, MapError, MappedStack, StackMapper, Stack, MIN_STACK_SIZE, STACK_ALIGNMENT,
+};
+
+const PAGE_SIZE: usize = 4096;
+
+#[derive(Default)]
+struct MockState {
+  mapped: Vec<(usize, usize)>,
+  protected: Vec<usize>,
+  fail_map: bool,
+  fail_protect: bool,
+}
+
+/// Stands in for the page tables of a kernel, handing out heap memory and
+/// recording which pages were mapped and protected.
+#[derive(Clone, Default)]
+struct MockMapper(Rc<RefCell<MockState>>);
+
+unsafe impl StackMapper for MockMapper {
+  fn page_size(&self) -> usize {
+    PAGE_SIZE
+  }
+
+  unsafe fn map_stack(&self, len: usize) -> Result<*mut u8, MapError> {
+    let mut state = self.0.borrow_mut();
+    if state.fail_map {
+      return Err(MapError);
+    }
+    assert_eq!(len % PAGE_SIZE, 0);
+    let ptr = alloc(Layout::from_size_align_unchecked(len, PAGE_SIZE));
+    assert!(!ptr.is_null());
+    state.mapped.push((ptr as usize, len));
+    Ok(ptr)
+  }
+
+  unsafe fn protect_stack(&self, ptr: *mut u8) -> Result<(), MapError> {
+    let mut state = self.0.borrow_mut();
+    if state.fail_protect {
+      return Err(MapError);
+    }
+    assert!(state.mapped.iter().any(|&(start, _)| start == ptr as usize));
+    state.protected.push(ptr as usize);
+    Ok(())
+  }
+
+  unsafe fn unmap_stack(&self, ptr: *mut u8, len: usize) -> Result<(), MapError> {
+    let mut state = self.0.borrow_mut();
+    let index = state
+      .mapped
+      .iter()
+      .position(|&mapping| mapping == (ptr as usize, len))
+      .expect("unmapping memory that was not mapped");
+    state.mapped.remove(index);
+    state.protected.retain(|&page| page != ptr as usize);
+    dealloc(ptr, Layout::from_size_align_unchecked(len, PAGE_SIZE));
+    Ok(())
+  }
+}
+
+#[test]
+fn default_mapped_stack() {
+  let mapper = MockMapper::default();
+  let stack = MappedStack::new(0, mapper.clone()).unwrap();
+  assert_eq!(stack.base() as usize & (STACK_ALIGNMENT - 1), 0);
+  assert_eq!(stack.limit() as usize & (STACK_ALIGNMENT - 1), 0);
+  assert_eq!(stack.base() as usize - stack.limit() as usize, MIN_STACK_SIZE);
+
+  // The guard page is directly below the limit, and the only page protected.
+  let state = mapper.0.borrow();
+  assert_eq!(state.mapped, [(stack.limit() as usize - PAGE_SIZE, MIN_STACK_SIZE + PAGE_SIZE)]);
+  assert_eq!(state.protected, [stack.limit() as usize - PAGE_SIZE]);
+
+  // Make sure the whole stack is accessible.
+  unsafe {
+    *(stack.base().offset(-1)) = 0;
+    *stack.limit() = 0;
+  }
+}
+
+#[test]
+fn rounds_up_to_pages() {
+  let mapper = MockMapper::default();
+  let stack = MappedStack::new(MIN_STACK_SIZE + 1, mapper.clone()).unwrap();
+  assert_eq!(stack.base() as usize - stack.limit() as usize, MIN_STACK_SIZE + PAGE_SIZE);
+  assert_eq!(mapper.0.borrow().mapped[0].1, MIN_STACK_SIZE + 2 * PAGE_SIZE);
+}
+
+#[test]
+fn drop_unmaps() {
+  let mapper = MockMapper::default();
+  let stack = MappedStack::new(0, mapper.clone()).unwrap();
+  assert_eq!(mapper.0.borrow().mapped.len(), 1);
+  drop(stack);
+  assert!(mapper.0.borrow().mapped.is_empty());
+  assert!(mapper.0.borrow().protected.is_empty());
+}
+
+#[test]
+fn map_failure() {
+  let mapper = MockMapper::default();
+  mapper.0.borrow_mut().fail_map = true;
+  assert_eq!(MappedStack::new(0, mapper.clone()).unwrap_err(), MapError);
+  assert!(mapper.0.borrow().mapped.is_empty());
+}
+
+#[test]
+fn protect_failure_unmaps() {
+  let mapper = MockMapper::default();
+  mapper.0.borrow_mut().fail_protect = true;
+  assert_eq!(MappedStack::new(0, mapper.clone()).unwrap_err(), MapError);
+  assert!(mapper.0.borrow().mapped.is_empty());
+}
+
+#[test]
+fn generator_on_mapped_stack() {
+  let mapper = MockMapper::default();
+  let stack = MappedStack::new(0, mapper.clone()).unwrap();
+  let mut gen = Generator::new(stack, |yielder, mut input: i32| {
+    while input != 0 {
+      input = yielder.suspend(input * 2);
+    }
+  });
+  assert_eq!(gen.resume(1), Some(2));
+  assert_eq!(gen.resume(21), Some(42));
+  assert_eq!(gen.resume(0), None);
+
+  drop(gen.unwrap());
+  assert!(mapper.0.borrow().mapped.is_empty());
+}